@@ -1,25 +1,98 @@
 mod utils;
-use crate::utils::connection_manager::dispatch_commands;
+use crate::utils::audit::open_audit_file;
+pub use crate::utils::auth::{hash_password, Auth};
+use crate::utils::connection_manager::{dispatch_commands, next_connection_id};
+pub use crate::utils::connection_manager::{LineWriter, TcpManager, TlsManager};
 use crate::utils::engine::{execute_transactions, Transaction};
-use rsheet_lib::connect::Manager;
+pub use crate::utils::functions::{ArgumentType, FunctionRegistry, UserFunction};
+use crate::utils::http_server::serve_http;
+pub use crate::utils::logging::{init_logging, LogFormat};
+pub use crate::utils::time::{Date, DateTime, Duration};
+pub use crate::utils::value::{CellArgument, CellValue};
+use crate::utils::websocket::serve_websocket;
+use rsheet_lib::connect::{Manager, Reader, ReaderWriter};
 use std::error::Error;
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
 use std::thread;
+use std::time::Instant;
 
-pub fn start_server<M>(mut manager: M) -> Result<(), Box<dyn Error>>
+// Front-ends served next to the connections of the manager
+#[derive(Clone, Debug, Default)]
+pub struct ServerOptions {
+    // Address of the HTTP REST API, none when not set
+    pub http_addr: Option<SocketAddr>,
+    // Address of the WebSocket endpoint streaming cell updates, none when not set
+    pub ws_addr: Option<SocketAddr>,
+    // Users allowed in and what they may do, anyone has full access when not set
+    pub auth: Option<Auth>,
+    // File every set is appended to as a line of JSON, sets are only kept in memory when not set
+    pub audit_log: Option<PathBuf>,
+}
+
+// Writer of the connections a manager accepts
+type ManagerWriter<M> = <<M as Manager>::ReaderWriter as ReaderWriter>::Writer;
+
+// Serve the spreadsheet to every connection the manager accepts
+// The JSON-lines protocol is offered on connections whose writer sends lines, see `LineWriter`
+pub fn start_server<M>(manager: M) -> Result<(), Box<dyn Error>>
 where
     M: Manager,
+    ManagerWriter<M>: LineWriter,
+{
+    start_server_with(manager, (), ServerOptions::default())
+}
+
+// Serve the spreadsheet to the manager's connections and the front-ends set in the options
+// Functions from the registry are callable from formulas next to the built-in ones
+pub fn start_server_with<M, R>(
+    mut manager: M,
+    registry: R,
+    options: ServerOptions,
+) -> Result<(), Box<dyn Error>>
+where
+    M: Manager,
+    ManagerWriter<M>: LineWriter,
+    R: FunctionRegistry,
 {
     let mut handles: Vec<thread::JoinHandle<()>> = Vec::new();
 
     let (tx, rx): (Sender<Transaction>, Receiver<Transaction>) = mpsc::channel();
 
-    let database_thread = thread::spawn(move || execute_transactions(rx));
+    if let Some(path) = &options.audit_log {
+        open_audit_file(path)?;
+    }
+    let auth = options.auth.map(Arc::new);
+    let functions = registry.functions();
+    let database_thread = thread::spawn(move || execute_transactions(rx, functions));
+
+    // The HTTP and WebSocket front-ends run until the process exits
+    if let Some(http_addr) = options.http_addr {
+        let listener = TcpListener::bind(http_addr)?;
+        let tx_clone = tx.clone();
+        let auth = auth.clone();
+        thread::spawn(move || serve_http(listener, tx_clone, auth));
+    }
+    if let Some(ws_addr) = options.ws_addr {
+        let listener = TcpListener::bind(ws_addr)?;
+        let auth = auth.clone();
+        thread::spawn(move || serve_websocket(listener, auth));
+    }
 
     while let Ok((recv, send)) = manager.accept_new_connection() {
         let tx_clone = tx.clone();
-        let handle = thread::spawn(move || dispatch_commands(recv, send, tx_clone));
+        let auth = auth.clone();
+        let connection = next_connection_id();
+        log::info!(connection, peer = recv.id().as_str(); "connection opened");
+        let handle = thread::spawn(move || {
+            let opened = Instant::now();
+            dispatch_commands(recv, send, tx_clone, auth, connection);
+            let duration_ms = opened.elapsed().as_millis() as u64;
+            log::info!(connection, duration_ms; "connection closed");
+        });
         handles.push(handle);
     }
 
@@ -31,3 +104,82 @@ where
     database_thread.join().unwrap();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsheet_lib::cell_value::CellValue as ReplyValue;
+    use rsheet_lib::connect::{ConnectionError, ReaderWriter, Writer};
+    use rsheet_lib::replies::Reply;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    // Manager of a single connection sending the given messages, keeping every reply
+    // Its writer only sends `Reply`s, like the writers of `rsheet_lib`'s `ConnectionManager`
+    struct ScriptManager {
+        connection: Option<(ScriptReader, ScriptWriter)>,
+    }
+
+    struct ScriptReader(VecDeque<String>);
+
+    struct ScriptWriter(Arc<Mutex<Vec<Reply>>>);
+
+    struct ScriptReaderWriter;
+
+    impl ReaderWriter for ScriptReaderWriter {
+        type Reader = ScriptReader;
+        type Writer = ScriptWriter;
+    }
+
+    impl Manager for ScriptManager {
+        type ReaderWriter = ScriptReaderWriter;
+
+        fn accept_new_connection(&mut self) -> Result<(ScriptReader, ScriptWriter), ()> {
+            self.connection.take().ok_or(())
+        }
+    }
+
+    impl Reader for ScriptReader {
+        fn read_message(&mut self) -> Result<String, ConnectionError> {
+            self.0.pop_front().ok_or(ConnectionError::ConnectionClosed)
+        }
+
+        fn id(&self) -> String {
+            String::from("script")
+        }
+    }
+
+    impl Writer for ScriptWriter {
+        fn write_message(&mut self, message: Reply) -> Result<(), ConnectionError> {
+            self.0.lock().unwrap().push(message);
+            Ok(())
+        }
+
+        fn id(&self) -> String {
+            String::from("script")
+        }
+    }
+
+    impl LineWriter for ScriptWriter {}
+
+    #[test]
+    fn serves_manager_without_line_writer() {
+        let messages = ["set ZZ1 5", "protocol json", "get ZZ1"];
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let manager = ScriptManager {
+            connection: Some((
+                ScriptReader(messages.iter().map(|msg| msg.to_string()).collect()),
+                ScriptWriter(Arc::clone(&replies)),
+            )),
+        };
+        start_server(manager).unwrap();
+
+        let replies = replies.lock().unwrap();
+        assert_eq!(replies.len(), 2);
+        assert!(matches!(&replies[0], Reply::Error(e) if e.contains("JSON protocol")));
+        assert_eq!(
+            replies[1],
+            Reply::Value(String::from("ZZ1"), ReplyValue::Int(5))
+        );
+    }
+}
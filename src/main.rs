@@ -1,8 +1,13 @@
 use std::error::Error;
+use std::io;
+use std::path::PathBuf;
 
 use clap::Parser;
-use rsheet::start_server;
-use rsheet_lib::connect::{resolve_address, ConnectionManager, TerminalManager};
+use rsheet::{
+    hash_password, init_logging, start_server_with, Auth, LogFormat, ServerOptions, TcpManager,
+    TlsManager,
+};
+use rsheet_lib::connect::{resolve_address, TerminalManager};
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -12,19 +17,81 @@ struct Args {
     /// Hides the contents of error messages
     #[arg(short, long, default_value_t = false)]
     mark_mode: bool,
+
+    /// Address to serve the HTTP REST API on
+    #[arg(long)]
+    http_addr: Option<String>,
+
+    /// Address to serve the WebSocket endpoint streaming cell updates on
+    #[arg(long)]
+    ws_addr: Option<String>,
+
+    /// Token connections log in with, granting full access
+    #[arg(long, conflicts_with = "users")]
+    auth_token: Option<String>,
+
+    /// File of users with the hash of their password and read or write grants
+    #[arg(long)]
+    users: Option<String>,
+
+    /// Reads a password from standard input and prints its hash for the users file
+    #[arg(long, exclusive = true)]
+    hash_password: bool,
+
+    /// File to append an audit entry to for every set
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    /// PEM file of the certificate chain to serve connections over TLS with
+    #[arg(long, requires_all = ["addr", "tls_key"])]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file of the private key of the TLS certificate
+    #[arg(long, requires_all = ["addr", "tls_cert"])]
+    tls_key: Option<PathBuf>,
+
+    /// Format of the log written to standard error, filtered by RUST_LOG
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::init();
-
     let args = Args::parse();
+    if args.hash_password {
+        let mut password = String::new();
+        io::stdin().read_line(&mut password)?;
+        println!(
+            "{}",
+            hash_password(password.trim_end_matches(['\r', '\n']))?
+        );
+        return Ok(());
+    }
+    init_logging(args.log_format);
+
+    let auth = match (args.auth_token, args.users) {
+        (Some(token), _) => Some(Auth::Token(token)),
+        (None, Some(path)) => Some(Auth::load_users(&path)?),
+        (None, None) => None,
+    };
+
+    let options = ServerOptions {
+        http_addr: args.http_addr.as_deref().map(resolve_address).transpose()?,
+        ws_addr: args.ws_addr.as_deref().map(resolve_address).transpose()?,
+        auth,
+        audit_log: args.audit_log,
+    };
 
     if let Some(addr) = args.addr {
         let addr = resolve_address(&addr)?;
-        let manager = ConnectionManager::launch(addr.ip(), addr.port());
-        start_server(manager)
+        if let (Some(cert), Some(key)) = (args.tls_cert, args.tls_key) {
+            let manager = TlsManager::launch(addr, &cert, &key)?;
+            start_server_with(manager, (), options)
+        } else {
+            let manager = TcpManager::launch(addr)?;
+            start_server_with(manager, (), options)
+        }
     } else {
         let manager = TerminalManager::launch(args.mark_mode);
-        start_server(manager)
+        start_server_with(manager, (), options)
     }
 }
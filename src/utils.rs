@@ -1,5 +1,22 @@
+pub mod audit;
+pub mod auth;
+mod base64;
+mod cell_format;
 mod command;
 pub mod connection_manager;
 mod database;
+mod decimal;
 mod dependency_manager;
 pub mod engine;
+mod evaluator;
+mod formula;
+pub mod functions;
+pub mod http_server;
+mod json_protocol;
+pub mod logging;
+mod metrics;
+mod parser;
+mod region_index;
+pub mod time;
+pub mod value;
+pub mod websocket;
@@ -0,0 +1,194 @@
+use crate::utils::database::{pos_to_cell_id, Shift};
+use crate::utils::json_protocol::typed_value;
+use crate::utils::time::DateTime;
+use crate::utils::value::CellValue;
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+// How an empty cell is shown in entries
+const EMPTY: &str = "(empty)";
+
+// Entries kept in memory, older ones are dropped and only remain in the audit file
+const MAX_ENTRIES: usize = 10_000;
+
+// A change of a cell applied to the sheet, with who made it
+// Written by `set`, by pasting with copy, move and fill, and by deleting or shifting
+// the rows and columns a formula refers to
+#[derive(Clone, Debug)]
+pub struct AuditEntry {
+    pub time: DateTime,
+    pub connection: u64,
+    pub user: Option<String>,
+    // Cell the change was made to, entries in memory follow inserted and deleted rows
+    // and columns while the audit file keeps the cell as it was addressed
+    pub cell: (u32, u32),
+    // Formula or value the cell held and holds, none if it was or is left empty
+    pub old_expr: Option<String>,
+    pub new_expr: Option<String>,
+    pub old_value: CellValue,
+    pub new_value: CellValue,
+}
+
+// The latest changes, oldest first
+lazy_static! {
+    static ref AUDIT_LOG: Mutex<VecDeque<AuditEntry>> = Mutex::new(VecDeque::new());
+}
+
+// File the entries are also written to, one JSON object per line
+lazy_static! {
+    static ref AUDIT_FILE: Mutex<Option<File>> = Mutex::new(None);
+}
+
+// Append every entry from now on to a file, which is created if needed
+pub fn open_audit_file(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *AUDIT_FILE.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+pub fn audit_record(entry: AuditEntry) {
+    if let Some(file) = AUDIT_FILE.lock().unwrap().as_mut() {
+        if let Err(e) = writeln!(file, "{}", entry.to_json()) {
+            log::error!("Could not write to the audit log: {}", e);
+        }
+    }
+    push_capped(&mut AUDIT_LOG.lock().unwrap(), entry);
+}
+
+fn push_capped(log: &mut VecDeque<AuditEntry>, entry: AuditEntry) {
+    if log.len() == MAX_ENTRIES {
+        log.pop_front();
+    }
+    log.push_back(entry);
+}
+
+// Move the entries in memory with the cells they are about
+// Entries of deleted cells are dropped, they remain in the audit file
+pub fn audit_shift(shift: &Shift) {
+    shift_entries(&mut AUDIT_LOG.lock().unwrap(), shift);
+}
+
+fn shift_entries(log: &mut VecDeque<AuditEntry>, shift: &Shift) {
+    log.retain_mut(|entry| match shift.apply(&entry.cell) {
+        Some(cell) => {
+            entry.cell = cell;
+            true
+        }
+        None => false,
+    });
+}
+
+// Entries of the changes of a cell still kept in memory, oldest first
+pub fn audit_entries(cell: &(u32, u32)) -> Vec<AuditEntry> {
+    AUDIT_LOG
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|entry| entry.cell == *cell)
+        .cloned()
+        .collect()
+}
+
+impl AuditEntry {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "time": self.time.to_string(),
+            "connection": self.connection,
+            "user": self.user,
+            "cell": pos_to_cell_id(&self.cell),
+            "old_expression": self.old_expr,
+            "new_expression": self.new_expr,
+            "old_value": typed_value(&self.old_value),
+            "new_value": typed_value(&self.new_value),
+        })
+    }
+}
+
+// `2024-05-01T09:30:00Z connection 3 (alice) A1: 5 -> B1 * 2, 5 -> 14`
+// An empty cell is shown as `(empty)`
+impl Display for AuditEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} connection {}", self.time, self.connection)?;
+        if let Some(user) = &self.user {
+            write!(f, " ({})", user)?;
+        }
+        write!(
+            f,
+            " {}: {} -> {}, {} -> {}",
+            pos_to_cell_id(&self.cell),
+            self.old_expr.as_deref().unwrap_or(EMPTY),
+            self.new_expr.as_deref().unwrap_or(EMPTY),
+            value_text(&self.old_value),
+            value_text(&self.new_value)
+        )
+    }
+}
+
+fn value_text(value: &CellValue) -> String {
+    match value {
+        CellValue::None => String::from(EMPTY),
+        value => value.to_text(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::database::Axis;
+
+    fn entry(cell: (u32, u32), old_expr: Option<&str>, new_expr: Option<&str>) -> AuditEntry {
+        AuditEntry {
+            time: DateTime::now(),
+            connection: 3,
+            user: Some(String::from("alice")),
+            cell,
+            old_expr: old_expr.map(String::from),
+            new_expr: new_expr.map(String::from),
+            old_value: old_expr.map_or(CellValue::None, |_| CellValue::Int(5)),
+            new_value: new_expr.map_or(CellValue::None, |_| CellValue::Int(14)),
+        }
+    }
+
+    #[test]
+    fn oldest_entries_are_dropped() {
+        let mut log = VecDeque::new();
+        for row in 1..=MAX_ENTRIES as u32 + 2 {
+            push_capped(&mut log, entry((0, row), None, Some("1")));
+        }
+        assert_eq!(log.len(), MAX_ENTRIES);
+        assert_eq!(log.front().unwrap().cell, (0, 3));
+        assert_eq!(log.back().unwrap().cell, (0, MAX_ENTRIES as u32 + 2));
+    }
+
+    #[test]
+    fn entries_follow_shifted_cells() {
+        let mut log = VecDeque::new();
+        for row in 1..=3 {
+            push_capped(&mut log, entry((1, row), None, Some("1")));
+        }
+        shift_entries(&mut log, &Shift::Delete(Axis::Row, 2));
+        let cells: Vec<_> = log.iter().map(|entry| entry.cell).collect();
+        assert_eq!(cells, [(1, 1), (1, 2)]);
+        shift_entries(&mut log, &Shift::Insert(Axis::Col, 0));
+        let cells: Vec<_> = log.iter().map(|entry| entry.cell).collect();
+        assert_eq!(cells, [(2, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn empty_cells_in_entries() {
+        let set = entry((0, 1), None, Some("B1 * 2")).to_string();
+        assert!(set.ends_with(" connection 3 (alice) A1: (empty) -> B1 * 2, (empty) -> 14"));
+        let cleared = entry((1, 2), Some("5"), None);
+        assert!(cleared
+            .to_string()
+            .ends_with(" connection 3 (alice) B2: 5 -> (empty), 5 -> (empty)"));
+        assert_eq!(cleared.to_json()["new_expression"], Value::Null);
+        assert_eq!(cleared.to_json()["old_expression"], json!("5"));
+    }
+}
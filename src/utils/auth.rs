@@ -0,0 +1,330 @@
+use crate::utils::base64;
+use crate::utils::database::{reference_region, Region};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use std::collections::HashMap;
+use std::fs;
+use subtle::ConstantTimeEq;
+
+// Who may use the spreadsheet, loaded at startup
+// Connections start with `login <token>` or `login <user> <password>` before any other command
+#[derive(Clone, Debug)]
+pub enum Auth {
+    // A single shared token, granting full access
+    Token(String),
+    // Users with the hash of their password and grants, read from a users file
+    Users(HashMap<String, User>),
+}
+
+#[derive(Clone, Debug)]
+pub struct User {
+    // Argon2 hash in the PHC string format, as `hash_password` makes
+    password_hash: String,
+    access: Access,
+}
+
+// A connection of a client, with the user logged in on it and what it may do
+#[derive(Clone, Debug)]
+pub struct Session {
+    // Number of the connection, unique while the server runs
+    pub connection: u64,
+    // Name of the user, none without authentication
+    pub user: Option<String>,
+    pub access: Access,
+}
+
+impl Session {
+    // Session of a connection with full access, as when no authentication is configured
+    pub fn anonymous(connection: u64) -> Self {
+        Session {
+            connection,
+            user: None,
+            access: Access::full(),
+        }
+    }
+}
+
+// What a logged in connection may read and write
+// Writing a cell allows reading it, and formulas written may only refer to cells that can be read
+#[derive(Clone, Debug, Default)]
+pub struct Access {
+    grants: Vec<Grant>,
+}
+
+#[derive(Clone, Debug)]
+struct Grant {
+    write: bool,
+    // `None` for the whole sheet
+    region: Option<Region>,
+}
+
+impl Auth {
+    // Read a users file, one user per line:
+    //   # name   password hash                        grants
+    //   alice    $argon2id$v=19$m=19456,t=2,p=1$...   write:*
+    //   bob      $argon2id$v=19$m=19456,t=2,p=1$...   read:* write:A1_C10
+    // Grants are `read:` or `write:` followed by `*` for the whole sheet or a cell range
+    // Hashes are made with `rsheet --hash-password`, plain passwords are refused
+    // The hashes can still be guessed at offline, so the file should only be readable
+    // by the user running the server
+    pub fn load_users(path: &str) -> Result<Auth, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut users = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words: Vec<_> = line.split_whitespace().collect();
+            let (name, password, grants) = match words.as_slice() {
+                [name, password, grants @ ..] => (name, password, grants),
+                _ => {
+                    return Err(format!(
+                        "{}:{}: expected a name and a password",
+                        path,
+                        number + 1
+                    ))
+                }
+            };
+            if PasswordHash::new(password).is_err() {
+                return Err(format!(
+                    "{}:{}: the password of {} is not a hash, make one with --hash-password",
+                    path,
+                    number + 1,
+                    name
+                ));
+            }
+            let grants = grants
+                .iter()
+                .map(|grant| {
+                    Grant::parse(grant)
+                        .ok_or_else(|| format!("{}:{}: invalid grant: {}", path, number + 1, grant))
+                })
+                .collect::<Result<_, _>>()?;
+            let user = User {
+                password_hash: password.to_string(),
+                access: Access { grants },
+            };
+            users.insert(name.to_string(), user);
+        }
+        Ok(Auth::Users(users))
+    }
+
+    // Access given by the arguments of `login`, `None` if they are wrong
+    pub fn login(&self, args: &str) -> Option<(String, Access)> {
+        let args_list: Vec<_> = args.split_whitespace().collect();
+        match (self, args_list.as_slice()) {
+            // Compared in constant time, so the time taken does not tell how much of it matched
+            (Auth::Token(token), [given])
+                if bool::from(given.as_bytes().ct_eq(token.as_bytes())) =>
+            {
+                Some((String::from("token"), Access::full()))
+            }
+            (Auth::Users(users), [name, password]) => {
+                let user = users.get(*name)?;
+                let hash = PasswordHash::new(&user.password_hash).ok()?;
+                let verified = Argon2::default().verify_password(password.as_bytes(), &hash);
+                verified
+                    .ok()
+                    .map(|_| (name.to_string(), user.access.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    // User and access given by the `Authorization` header of an HTTP request,
+    // `Bearer <token>` or `Basic` with the name and password of a user
+    pub fn authorize(&self, header: &str) -> Option<(String, Access)> {
+        let (scheme, credentials) = header.trim().split_once(' ')?;
+        let args = match scheme {
+            "Bearer" => credentials.trim().to_string(),
+            "Basic" => {
+                let decoded = String::from_utf8(base64::decode(credentials.trim())?).ok()?;
+                let (name, password) = decoded.split_once(':')?;
+                format!("{} {}", name, password)
+            }
+            _ => return None,
+        };
+        self.login(&args)
+    }
+}
+
+impl Access {
+    // Access to the whole sheet, as when no authentication is configured
+    pub fn full() -> Self {
+        Access {
+            grants: vec![Grant {
+                write: true,
+                region: None,
+            }],
+        }
+    }
+
+    pub fn can_read(&self, cells: &[(u32, u32)]) -> bool {
+        cells
+            .iter()
+            .all(|cell| self.grants.iter().any(|grant| grant.covers(cell)))
+    }
+
+    pub fn can_write(&self, cells: &[(u32, u32)]) -> bool {
+        cells.iter().all(|cell| {
+            self.grants
+                .iter()
+                .any(|grant| grant.write && grant.covers(cell))
+        })
+    }
+
+    // Whether every cell of the region may be read, without going through its cells one by one
+    pub fn can_read_region(&self, region: &Region) -> bool {
+        self.covers_region(region, |cell| self.can_read(&[*cell]))
+    }
+
+    // Whether every cell of the region may be written, without going through its cells one by one
+    pub fn can_write_region(&self, region: &Region) -> bool {
+        self.covers_region(region, |cell| self.can_write(&[*cell]))
+    }
+
+    fn covers_region(&self, region: &Region, covers: impl Fn(&(u32, u32)) -> bool) -> bool {
+        let ((left, top), (right, bottom)) = region.bounds();
+        // Cut the region where the sides of the grants are, no grant begins or ends inside a piece
+        // so the first cell of each piece tells whether the whole piece is covered
+        let mut cols = vec![left];
+        let mut rows = vec![top];
+        for grant in self.grants.iter() {
+            let ((grant_left, grant_top), (grant_right, grant_bottom)) = match grant.region {
+                Some(region) => region.bounds(),
+                None => continue,
+            };
+            cols.push(grant_left);
+            cols.extend(grant_right.checked_add(1));
+            rows.push(grant_top);
+            rows.extend(grant_bottom.checked_add(1));
+        }
+        cols.retain(|col| (left..=right).contains(col));
+        rows.retain(|row| (top..=bottom).contains(row));
+        cols.iter()
+            .all(|col| rows.iter().all(|row| covers(&(*col, *row))))
+    }
+
+    // Whether the connection may make changes to the whole sheet, like inserting rows
+    pub fn can_write_sheet(&self) -> bool {
+        self.grants
+            .iter()
+            .any(|grant| grant.write && grant.region.is_none())
+    }
+}
+
+impl Grant {
+    fn parse(text: &str) -> Option<Self> {
+        let (kind, range) = text.split_once(':')?;
+        let write = match kind {
+            "read" => false,
+            "write" => true,
+            _ => return None,
+        };
+        let region = match range {
+            "*" => None,
+            range => Some(reference_region(range)?),
+        };
+        Some(Grant { write, region })
+    }
+
+    fn covers(&self, cell: &(u32, u32)) -> bool {
+        self.region.is_none_or(|region| region.contains(cell))
+    }
+}
+
+// Hash of a password for the users file, with a random salt
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    match Argon2::default().hash_password(password.as_bytes(), &salt) {
+        Ok(hash) => Ok(hash.to_string()),
+        Err(e) => Err(format!("Could not hash the password: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(grants: &[&str]) -> Access {
+        Access {
+            grants: grants
+                .iter()
+                .map(|grant| Grant::parse(grant).unwrap())
+                .collect(),
+        }
+    }
+
+    fn region(variable: &str) -> Region {
+        reference_region(variable).unwrap()
+    }
+
+    fn users_file(name: &str, text: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("rsheet-users-{}-{}", std::process::id(), name));
+        fs::write(&path, text).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn users_log_in_with_hashed_passwords() {
+        let hash = hash_password("s3cret").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        let path = users_file("hashed", &format!("alice {} read:A1_B2\n", hash));
+        let auth = Auth::load_users(&path).unwrap();
+        let (name, access) = auth.login("alice s3cret").unwrap();
+        assert_eq!(name, "alice");
+        assert!(access.can_read(&[(1, 2)]) && !access.can_read(&[(2, 2)]));
+        assert!(auth.login("alice hunter2").is_none());
+        assert!(auth.login("bob s3cret").is_none());
+        assert!(auth.login(&hash).is_none());
+    }
+
+    #[test]
+    fn plain_passwords_are_refused() {
+        let path = users_file("plain", "alice s3cret write:*\n");
+        let e = Auth::load_users(&path).unwrap_err();
+        assert!(
+            e.ends_with(":1: the password of alice is not a hash, make one with --hash-password")
+        );
+    }
+
+    #[test]
+    fn token_login() {
+        let auth = Auth::Token(String::from("abc123"));
+        assert_eq!(auth.login("abc123").unwrap().0, "token");
+        assert!(auth.login("abc12").is_none());
+        assert!(auth.login("abc1234").is_none());
+        assert!(auth.authorize("Bearer abc123").is_some());
+    }
+
+    #[test]
+    fn read_region_within_grants() {
+        let bob = access(&["read:A1_C10", "write:D1_D10"]);
+        assert!(bob.can_read_region(&region("B2_C5")));
+        assert!(bob.can_read_region(&region("C5_A1")));
+        // Covered by the two grants together
+        assert!(bob.can_read_region(&region("A1_D10")));
+        assert!(!bob.can_read_region(&region("A1_D11")));
+        assert!(!bob.can_read_region(&region("A1_E1")));
+        assert!(!bob.can_read_region(&region("A_A")));
+    }
+
+    #[test]
+    fn read_region_with_gaps_between_grants() {
+        let carol = access(&["read:A1_A5", "read:A7_A10", "read:B1_B10"]);
+        assert!(carol.can_read_region(&region("A1_A5")));
+        assert!(!carol.can_read_region(&region("A1_B10")));
+        assert!(carol.can_read_region(&region("B1_B10")));
+    }
+
+    #[test]
+    fn read_open_regions() {
+        assert!(Access::full().can_read_region(&region("A_C")));
+        assert!(access(&["read:B_B"]).can_read_region(&region("B3_B1000000")));
+        assert!(!access(&["read:B_B"]).can_read_region(&region("2_2")));
+        assert!(!Access::default().can_read_region(&region("A1")));
+    }
+}
@@ -0,0 +1,182 @@
+use crate::utils::decimal::{Decimal, MAX_SCALE};
+use crate::utils::time::DateTime;
+use crate::utils::value::CellValue;
+use std::fmt::{self, Display, Formatter};
+
+// Currencies with a symbol of their own, and the number of decimals amounts are shown with
+// Any other three-letter code is written in front of the amount with two decimals
+const CURRENCIES: &[(&str, &str, u32)] = &[
+    ("USD", "$", 2),
+    ("EUR", "€", 2),
+    ("GBP", "£", 2),
+    ("JPY", "¥", 0),
+    ("CNY", "¥", 2),
+    ("INR", "₹", 2),
+    ("AUD", "A$", 2),
+    ("CAD", "C$", 2),
+];
+
+const MONTH_NAMES: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+// Date specifiers understood by `date:` formats, each followed by `%`
+const DATE_SPECIFIERS: &str = "YymdHMSbB%";
+
+// How a cell is displayed by `get --formatted`, the stored value is never changed
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum CellFormat {
+    // `currency:USD`, an amount with its currency symbol and thousands separators
+    Currency(String),
+    // `percent:2`, a fraction shown as a percentage with the given number of decimals
+    Percent(u32),
+    // `date:%Y-%m-%d`, a date or date-time laid out by a strftime-like pattern
+    Date(String),
+}
+
+impl CellFormat {
+    // Parse `currency:<code>`, `percent:<decimals>` or `date:<pattern>`
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (kind, argument) = text
+            .split_once(':')
+            .ok_or_else(|| format!("Unknown format: {}", text))?;
+        match kind {
+            "currency"
+                if argument.len() == 3 && argument.bytes().all(|b| b.is_ascii_uppercase()) =>
+            {
+                Ok(CellFormat::Currency(argument.to_string()))
+            }
+            "currency" => Err(format!("Unknown currency: {}", argument)),
+            "percent" => match argument.parse::<u32>() {
+                Ok(decimals) if decimals <= MAX_SCALE => Ok(CellFormat::Percent(decimals)),
+                _ => Err(format!("Invalid number of decimals: {}", argument)),
+            },
+            "date" => {
+                let mut chars = argument.chars();
+                while let Some(ch) = chars.next() {
+                    if ch == '%' && !chars.next().is_some_and(|ch| DATE_SPECIFIERS.contains(ch)) {
+                        return Err(format!("Invalid date pattern: {}", argument));
+                    }
+                }
+                Ok(CellFormat::Date(argument.to_string()))
+            }
+            _ => Err(format!("Unknown format: {}", text)),
+        }
+    }
+
+    // Text of a value in this format
+    // Values the format does not apply to, like text in a currency cell, are shown as they are
+    pub fn render(&self, value: &CellValue) -> String {
+        let rendered = match self {
+            CellFormat::Currency(code) => currency(code, value),
+            CellFormat::Percent(decimals) => percent(*decimals, value),
+            CellFormat::Date(pattern) => match value {
+                CellValue::Date(date) => Some(date_pattern(pattern, date.at_midnight())),
+                CellValue::DateTime(moment) => Some(date_pattern(pattern, *moment)),
+                _ => None,
+            },
+        };
+        rendered.unwrap_or_else(|| value.to_text())
+    }
+}
+
+impl Display for CellFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CellFormat::Currency(code) => write!(f, "currency:{}", code),
+            CellFormat::Percent(decimals) => write!(f, "percent:{}", decimals),
+            CellFormat::Date(pattern) => write!(f, "date:{}", pattern),
+        }
+    }
+}
+
+// A number rounded to `decimals` digits after the point, exactly for integers and decimals
+fn fixed(value: &CellValue, decimals: u32) -> Option<String> {
+    match value {
+        CellValue::Float(number) => Some(format!("{:.*}", decimals as usize, number)),
+        value => Some(value.to_decimal()?.rescale(decimals)?.to_string()),
+    }
+}
+
+// Insert thousands separators into the whole part of a number
+fn group_thousands(number: &str) -> String {
+    let (sign, number) = match number.strip_prefix('-') {
+        Some(number) => ("-", number),
+        None => ("", number),
+    };
+    let (whole, fraction) = match number.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (number, None),
+    };
+    let mut grouped = String::new();
+    for (position, digit) in whole.chars().enumerate() {
+        if position > 0 && (whole.len() - position) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    match fraction {
+        Some(fraction) => format!("{}{}.{}", sign, grouped, fraction),
+        None => format!("{}{}", sign, grouped),
+    }
+}
+
+fn currency(code: &str, value: &CellValue) -> Option<String> {
+    let (symbol, decimals) = match CURRENCIES.iter().find(|(known, ..)| *known == code) {
+        Some((_, symbol, decimals)) => (symbol.to_string(), *decimals),
+        None => (format!("{} ", code), 2),
+    };
+    let amount = group_thousands(&fixed(value, decimals)?);
+    match amount.strip_prefix('-') {
+        Some(amount) => Some(format!("-{}{}", symbol, amount)),
+        None => Some(format!("{}{}", symbol, amount)),
+    }
+}
+
+fn percent(decimals: u32, value: &CellValue) -> Option<String> {
+    let scaled = match value {
+        CellValue::Float(number) => CellValue::Float(number * 100.0),
+        value => CellValue::Decimal(value.to_decimal()?.checked_mul(&Decimal::from_int(100))?),
+    };
+    Some(format!("{}%", fixed(&scaled, decimals)?))
+}
+
+fn date_pattern(pattern: &str, moment: DateTime) -> String {
+    let (year, month, day) = moment.date().year_month_day();
+    let (hours, minutes, seconds) = moment.time();
+    let month_name = MONTH_NAMES[month as usize - 1];
+
+    let mut text = String::new();
+    let mut chars = pattern.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '%' {
+            text.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => text.push_str(&format!("{:04}", year)),
+            Some('y') => text.push_str(&format!("{:02}", year.rem_euclid(100))),
+            Some('m') => text.push_str(&format!("{:02}", month)),
+            Some('d') => text.push_str(&format!("{:02}", day)),
+            Some('H') => text.push_str(&format!("{:02}", hours)),
+            Some('M') => text.push_str(&format!("{:02}", minutes)),
+            Some('S') => text.push_str(&format!("{:02}", seconds)),
+            Some('b') => text.push_str(&month_name[..3]),
+            Some('B') => text.push_str(month_name),
+            Some(other) => text.push(other),
+            None => text.push('%'),
+        }
+    }
+    text
+}
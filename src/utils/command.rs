@@ -1,49 +1,527 @@
-use crate::utils::database::{database_get_value, split_cell_id};
-use rsheet_lib::command_runner::CellValue;
+use crate::utils::audit::audit_entries;
+use crate::utils::auth::{Access, Session};
+use crate::utils::cell_format::CellFormat;
+use crate::utils::database::{
+    column_number, database_get_value, is_valid_name, names_get, names_list, offset_reference,
+    parse_to_indices, range_too_large, reference_region, resolve_name, split_cell_id,
+    split_reference, tables_list, Axis, Region, Shift, MAX_RANGE_CELLS,
+};
+use crate::utils::formula::rewrite_words;
 use rsheet_lib::replies::Reply;
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
+use std::time::Instant;
 
-use crate::utils::engine::Transaction;
+use crate::utils::engine::{PastedCell, Request, Transaction};
+use crate::utils::metrics::record_command;
+use crate::utils::parser::parse;
+use crate::utils::value::CellValue;
+
+// Outcome of a command, holding values with their rsheet type
+// Replies of the text protocol carry the values `rsheet_lib` knows, see `Response::into_reply`
+#[derive(Clone, PartialEq, Debug)]
+pub enum Response {
+    Value(String, CellValue),
+    Error(String),
+}
+
+impl Response {
+    pub fn into_reply(self) -> Reply {
+        match self {
+            Response::Value(name, value) => Reply::Value(name, value.to_reply_value()),
+            Response::Error(e) => Reply::Error(e),
+        }
+    }
+}
 
 pub enum Command {
     Set(String),
     Get(String),
+    Insert(String),
+    Delete(String),
+    Copy(String),
+    Move(String),
+    Fill(String),
+    Name(String),
+    Table(String),
+    Format(String),
+    Protect(String),
+    Unprotect(String),
+    Audit(String),
     Unsupported,
 }
 
 impl Command {
-    // execute a command
-    pub fn execute(&self, transactions_sender: &Sender<Transaction>) -> Option<Reply> {
+    // execute a command, if the connection's access allows it
+    pub fn execute(
+        &self,
+        transactions_sender: &Sender<Transaction>,
+        session: &Session,
+    ) -> Option<Response> {
+        let started = Instant::now();
+        let response = self.run(transactions_sender, session);
+        log::debug!(
+            connection = session.connection,
+            command = self.name(),
+            cell = self.target(),
+            ok = !matches!(response, Some(Response::Error(_))),
+            duration_us = started.elapsed().as_micros() as u64;
+            "command"
+        );
+        response
+    }
+
+    fn run(
+        &self,
+        transactions_sender: &Sender<Transaction>,
+        session: &Session,
+    ) -> Option<Response> {
+        if !self.is_permitted(&session.access) {
+            return Some(Response::Error(String::from("Error: Permission denied")));
+        }
         match self {
-            Command::Set(args) => Self::handle_set(args, transactions_sender),
+            Command::Set(args) => Self::handle_set(args, transactions_sender, session),
             Command::Get(args) => Some(Self::handle_get(args)),
-            Command::Unsupported => Some(Reply::Error(String::from("Unsupported Command"))),
+            Command::Insert(args) => {
+                Self::handle_shift(args, Shift::Insert, transactions_sender, session)
+            }
+            Command::Delete(args) => {
+                Self::handle_shift(args, Shift::Delete, transactions_sender, session)
+            }
+            Command::Copy(args) => Self::handle_paste(args, false, transactions_sender, session),
+            Command::Move(args) => Self::handle_paste(args, true, transactions_sender, session),
+            Command::Fill(args) => Self::handle_fill(args, transactions_sender, session),
+            Command::Name(args) => Self::handle_name(args, transactions_sender, session),
+            Command::Table(args) => Self::handle_table(args, transactions_sender, session),
+            Command::Format(args) => Self::handle_format(args, transactions_sender, session),
+            Command::Protect(args) => {
+                Self::handle_protect(args, true, transactions_sender, session)
+            }
+            Command::Unprotect(args) => {
+                Self::handle_protect(args, false, transactions_sender, session)
+            }
+            Command::Audit(args) => Some(Self::handle_audit(args)),
+            Command::Unsupported => Some(Response::Error(String::from("Unsupported Command"))),
+        }
+    }
+
+    // Word the command is sent with, `unsupported` for anything else
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Set(_) => "set",
+            Command::Get(_) => "get",
+            Command::Insert(_) => "insert",
+            Command::Delete(_) => "delete",
+            Command::Copy(_) => "copy",
+            Command::Move(_) => "move",
+            Command::Fill(_) => "fill",
+            Command::Name(_) => "name",
+            Command::Table(_) => "table",
+            Command::Format(_) => "format",
+            Command::Protect(_) => "protect",
+            Command::Unprotect(_) => "unprotect",
+            Command::Audit(_) => "audit",
+            Command::Unsupported => "unsupported",
+        }
+    }
+
+    // Cell or range a command applies to, the first argument that is not a flag
+    fn target(&self) -> Option<&str> {
+        match self {
+            Command::Set(args)
+            | Command::Get(args)
+            | Command::Copy(args)
+            | Command::Move(args)
+            | Command::Fill(args)
+            | Command::Format(args)
+            | Command::Protect(args)
+            | Command::Unprotect(args)
+            | Command::Audit(args) => args.split_whitespace().find(|arg| !arg.starts_with("--")),
+            _ => None,
+        }
+    }
+
+    // Whether the access covers the cells the command reads and writes
+    // Commands that do not parse, ranges too large and destinations off the sheet are permitted,
+    // their handler reports the error
+    fn is_permitted(&self, access: &Access) -> bool {
+        match self {
+            Command::Set(args) => {
+                let args = args.strip_prefix("--force ").unwrap_or(args);
+                let (cell, expr) = args.split_once(' ').unwrap_or((args, ""));
+                split_cell_id(cell).is_none_or(|cell| access.can_write(&[cell]))
+                    && can_read_formula(expr, access)
+            }
+            Command::Get(args) | Command::Audit(args) => {
+                let cell = args.split_whitespace().last().and_then(split_cell_id);
+                cell.is_none_or(|cell| access.can_read(&[cell]))
+            }
+            Command::Copy(args) | Command::Move(args) => {
+                let args_list: Vec<_> = args.split_whitespace().collect();
+                let (region, dest) = match args_list.as_slice() {
+                    [range, dest] => (pasted_region(range), split_cell_id(dest)),
+                    _ => return true,
+                };
+                let (region, dest) = match region.zip(dest) {
+                    Some(region_dest) => region_dest,
+                    None => return true,
+                };
+                let pasted = match paste_positions(&region.rows().concat(), dest) {
+                    Some(pasted) => pasted,
+                    None => return true,
+                };
+                let source_permitted = match self {
+                    Command::Move(_) => access.can_write_region(&region),
+                    _ => access.can_read_region(&region),
+                };
+                let destinations: Vec<_> = pasted.iter().map(|(_, to)| *to).collect();
+                source_permitted
+                    && access.can_write(&destinations)
+                    && can_read_pasted(&pasted, access)
+            }
+            Command::Fill(args) => {
+                let args_list: Vec<_> = args.split_whitespace().collect();
+                let cells = match args_list.as_slice() {
+                    [source, range] => split_cell_id(source).zip(pasted_region(range)),
+                    _ => None,
+                };
+                cells.is_none_or(|(source, region)| {
+                    let pasted: Vec<_> = region
+                        .rows()
+                        .concat()
+                        .into_iter()
+                        .map(|cell| (source, cell))
+                        .collect();
+                    access.can_read(&[source])
+                        && access.can_write_region(&region)
+                        && can_read_pasted(&pasted, access)
+                })
+            }
+            Command::Format(args) | Command::Protect(args) | Command::Unprotect(args) => {
+                let range = args.split_whitespace().next();
+                let region = range.and_then(|range| reference_region(&resolve_name(range)));
+                region.is_none_or(|region| access.can_write_region(&region))
+            }
+            // Shifts, names and tables change the layout of the whole sheet
+            Command::Insert(_) | Command::Delete(_) => access.can_write_sheet(),
+            Command::Name(args) | Command::Table(args) if args.trim() != "list" => {
+                access.can_write_sheet()
+            }
+            _ => true,
         }
     }
 
-    fn handle_set(args: &str, transactions_sender: &Sender<Transaction>) -> Option<Reply> {
+    // Handle `set A1 <expr>`, rejecting expressions that do not parse
+    // `set --force A1 <expr>` stores the expression anyway, the cell then holds the parse error
+    fn handle_set(
+        args: &str,
+        transactions_sender: &Sender<Transaction>,
+        session: &Session,
+    ) -> Option<Response> {
+        let (force, args) = match args.strip_prefix("--force ") {
+            Some(args) => (true, args),
+            None => (false, args),
+        };
         let args_list: Vec<String> = args.splitn(2, ' ').map(|s| s.to_string()).collect();
         if args_list.len() == 1 {
-            return Some(Reply::Error(format!(
+            return Some(Response::Error(format!(
                 "Error: Error parsing request: {}",
                 args
             )));
         }
+        if !force {
+            if let Err(e) = parse(&args_list[1]) {
+                return Some(Response::Error(format!("Error: Invalid formula: {}", e)));
+            }
+        }
 
         // Send set request to worker thread for dependency update
-        let (resp_tx, resp_rx): (Sender<Option<Reply>>, Receiver<Option<Reply>>) = mpsc::channel();
-        let transaction = Transaction::new(args_list, resp_tx);
+        let request = Request::Set(args_list[0].clone(), args_list[1].clone());
+        Self::send_request(request, transactions_sender, session)
+    }
+
+    // Handle `insert row 3`, `delete col B` and friends
+    fn handle_shift(
+        args: &str,
+        shift: fn(Axis, u32) -> Shift,
+        transactions_sender: &Sender<Transaction>,
+        session: &Session,
+    ) -> Option<Response> {
+        let args_list: Vec<_> = args.split_whitespace().collect();
+        let axis_index = match args_list.as_slice() {
+            ["row", row] => row.parse::<u32>().ok().map(|row| (Axis::Row, row)),
+            ["col", col] => column_number(col).map(|col| (Axis::Col, col)),
+            _ => None,
+        };
+
+        match axis_index {
+            Some((axis, index)) => Self::send_request(
+                Request::Shift(shift(axis, index)),
+                transactions_sender,
+                session,
+            ),
+            None => Some(Response::Error(format!(
+                "Error: Error parsing request: {}",
+                args
+            ))),
+        }
+    }
+
+    // Handle `copy A1_B2 D1` and `move A1_B2 D1`
+    // The range is pasted with its top left corner at the destination cell
+    fn handle_paste(
+        args: &str,
+        cut: bool,
+        transactions_sender: &Sender<Transaction>,
+        session: &Session,
+    ) -> Option<Response> {
+        let args_list: Vec<_> = args.split_whitespace().collect();
+        let cells = match args_list.as_slice() {
+            [range, _] if range_too_large(range) => return Some(too_large(range)),
+            [range, dest] => parse_to_indices(range).zip(split_cell_id(dest)),
+            _ => None,
+        };
+
+        match cells {
+            Some((cells, dest)) => match paste_positions(&cells, dest) {
+                Some(cells) => {
+                    Self::send_request(Request::Paste { cells, cut }, transactions_sender, session)
+                }
+                None => Some(Response::Error(format!(
+                    "Error: Invalid destination: {}",
+                    args_list[1]
+                ))),
+            },
+            None => Some(Response::Error(format!(
+                "Error: Error parsing request: {}",
+                args
+            ))),
+        }
+    }
+
+    // Handle `fill A1 A2_A10`, copying one cell into every cell of a range
+    fn handle_fill(
+        args: &str,
+        transactions_sender: &Sender<Transaction>,
+        session: &Session,
+    ) -> Option<Response> {
+        let args_list: Vec<_> = args.split_whitespace().collect();
+        let cells = match args_list.as_slice() {
+            [_, range] if range_too_large(range) => return Some(too_large(range)),
+            [source, range] => split_cell_id(source).zip(parse_to_indices(range)),
+            _ => None,
+        };
+
+        match cells {
+            Some((source, range)) => {
+                let cells = range.into_iter().map(|cell| (source, cell)).collect();
+                Self::send_request(
+                    Request::Paste { cells, cut: false },
+                    transactions_sender,
+                    session,
+                )
+            }
+            None => Some(Response::Error(format!(
+                "Error: Error parsing request: {}",
+                args
+            ))),
+        }
+    }
+
+    // Handle `name define Sales A1_A10`, `name list` and `name drop Sales`
+    fn handle_name(
+        args: &str,
+        transactions_sender: &Sender<Transaction>,
+        session: &Session,
+    ) -> Option<Response> {
+        let args_list: Vec<_> = args.split_whitespace().collect();
+        match args_list.as_slice() {
+            ["define", name, reference]
+                if is_valid_name(name) && split_reference(reference).is_some() =>
+            {
+                let request = Request::DefineName(name.to_string(), reference.to_string());
+                Self::send_request(request, transactions_sender, session)
+            }
+            ["list"] => {
+                let names: Vec<_> = names_list()
+                    .into_iter()
+                    .map(|(name, reference)| format!("{}={}", name, reference))
+                    .collect();
+                Some(Response::Value(
+                    String::from("names"),
+                    CellValue::String(names.join(", ")),
+                ))
+            }
+            ["drop", name] => match names_get(name) {
+                Some(_) => Self::send_request(
+                    Request::DropName(name.to_string()),
+                    transactions_sender,
+                    session,
+                ),
+                None => Some(Response::Error(format!("Error: Unknown name: {}", name))),
+            },
+            _ => Some(Response::Error(format!(
+                "Error: Error parsing request: {}",
+                args
+            ))),
+        }
+    }
+
+    // Handle `table create Orders A1_F500` and `table list`
+    // A table needs a header row and at least one row of data
+    fn handle_table(
+        args: &str,
+        transactions_sender: &Sender<Transaction>,
+        session: &Session,
+    ) -> Option<Response> {
+        let args_list: Vec<_> = args.split_whitespace().collect();
+        match args_list.as_slice() {
+            ["create", table, range] if is_valid_name(table) => {
+                match split_reference(range).as_deref() {
+                    Some([start, end])
+                        if start.position().is_some()
+                            && end.position().is_some()
+                            && start.row != end.row =>
+                    {
+                        let request = Request::CreateTable(table.to_string(), range.to_string());
+                        Self::send_request(request, transactions_sender, session)
+                    }
+                    _ => Some(Response::Error(format!(
+                        "Error: Error parsing request: {}",
+                        args
+                    ))),
+                }
+            }
+            ["list"] => {
+                let tables: Vec<_> = tables_list()
+                    .into_iter()
+                    .map(|(table, reference)| format!("{}={}", table, reference))
+                    .collect();
+                Some(Response::Value(
+                    String::from("tables"),
+                    CellValue::String(tables.join(", ")),
+                ))
+            }
+            _ => Some(Response::Error(format!(
+                "Error: Error parsing request: {}",
+                args
+            ))),
+        }
+    }
+
+    // Handle `format A1_A100 currency:USD`, `percent:2`, `date:%Y-%m-%d` and `format A1 clear`
+    fn handle_format(
+        args: &str,
+        transactions_sender: &Sender<Transaction>,
+        session: &Session,
+    ) -> Option<Response> {
+        let (range, format) = match args.trim().split_once(' ') {
+            Some((range, format)) => (range, format.trim()),
+            None => {
+                return Some(Response::Error(format!(
+                    "Error: Error parsing request: {}",
+                    args
+                )))
+            }
+        };
+        if range_too_large(range) {
+            return Some(too_large(range));
+        }
+        let cells = match parse_to_indices(range) {
+            Some(cells) => cells,
+            None => {
+                return Some(Response::Error(format!(
+                    "Error: Invalid Key Provided: {}",
+                    range
+                )))
+            }
+        };
+        let format = match format {
+            "clear" => None,
+            format => match CellFormat::parse(format) {
+                Ok(format) => Some(format),
+                Err(e) => return Some(Response::Error(format!("Error: {}", e))),
+            },
+        };
+        Self::send_request(Request::Format(cells, format), transactions_sender, session)
+    }
+
+    // Handle `protect A1_B5` and `unprotect A1_B5`
+    fn handle_protect(
+        args: &str,
+        protected: bool,
+        transactions_sender: &Sender<Transaction>,
+        session: &Session,
+    ) -> Option<Response> {
+        let args_list: Vec<_> = args.split_whitespace().collect();
+        match args_list.as_slice() {
+            [range] if range_too_large(range) => Some(too_large(range)),
+            [range] => match parse_to_indices(range) {
+                Some(cells) => Self::send_request(
+                    Request::Protect(cells, protected),
+                    transactions_sender,
+                    session,
+                ),
+                None => Some(Response::Error(format!(
+                    "Error: Invalid Key Provided: {}",
+                    range
+                ))),
+            },
+            _ => Some(Response::Error(format!(
+                "Error: Error parsing request: {}",
+                args
+            ))),
+        }
+    }
+
+    // Handle `audit A1`, listing the latest changes of the cell with when and by whom they were made
+    fn handle_audit(args: &str) -> Response {
+        let args_list: Vec<_> = args.split_whitespace().collect();
+        match args_list.as_slice() {
+            [cell_id] => match split_cell_id(cell_id) {
+                Some(cell_position) => {
+                    let entries: Vec<_> = audit_entries(&cell_position)
+                        .iter()
+                        .map(|entry| entry.to_string())
+                        .collect();
+                    Response::Value(cell_id.to_string(), CellValue::String(entries.join("; ")))
+                }
+                None => Response::Error(format!("Error: Invalid Key Provided: {}", cell_id)),
+            },
+            _ => Response::Error(format!("Error: Error parsing request: {}", args)),
+        }
+    }
+
+    // Wait until the requests sent before by the connection are applied,
+    // so that a read running on another thread sees their changes
+    pub fn sync(transactions_sender: &Sender<Transaction>, session: &Session) {
+        Self::send_request(Request::Sync, transactions_sender, session);
+    }
+
+    // Send a request to the worker thread and wait for its reply
+    fn send_request(
+        request: Request,
+        transactions_sender: &Sender<Transaction>,
+        session: &Session,
+    ) -> Option<Response> {
+        let (resp_tx, resp_rx): (Sender<Option<Response>>, Receiver<Option<Response>>) =
+            mpsc::channel();
+        let transaction = Transaction::new(request, session.clone(), resp_tx);
         transactions_sender.send(transaction).unwrap();
 
         // Wait for results to return
         resp_rx.recv().unwrap()
     }
 
-    fn handle_get(args: &String) -> Reply {
-        let args_list: Vec<_> = args.split_whitespace().collect();
+    // Handle `get A1`, and `get --formatted A1` which renders the value through the cell's format
+    fn handle_get(args: &String) -> Response {
+        let mut args_list: Vec<_> = args.split_whitespace().collect();
+        let formatted = args_list.first() == Some(&"--formatted");
+        if formatted {
+            args_list.remove(0);
+        }
         if args_list.len() != 1 {
-            return Reply::Error(format!("Error: Error parsing request: {}", args));
+            return Response::Error(format!("Error: Error parsing request: {}", args));
         }
         if let Some(cell_position) = split_cell_id(args_list[0]) {
             std::thread::sleep(std::time::Duration::from_millis(10));
@@ -52,21 +530,184 @@ impl Command {
             let cell_ref = database_get_value(&cell_position);
             if cell_ref.dependency.is_some() {
                 if let CellValue::Error(e) = cell_ref.cell_value {
-                    return Reply::Error(e);
+                    return Response::Error(e);
                 }
             }
-            Reply::Value(args_list[0].to_string(), cell_ref.cell_value)
+            let value = match &cell_ref.format {
+                Some(format) if formatted => CellValue::String(format.render(&cell_ref.cell_value)),
+                _ => cell_ref.cell_value,
+            };
+            Response::Value(args_list[0].to_string(), value)
         } else {
-            Reply::Error(format!("Error: Invalid Key Provided: {}", args))
+            Response::Error(format!("Error: Invalid Key Provided: {}", args))
         }
     }
 }
 
+// Whether the access may read every cell a formula refers to, through names and tables too,
+// so that a formula cannot reveal cells the connection may not read
+// Formulas that do not parse refer to nothing
+fn can_read_formula(expr: &str, access: &Access) -> bool {
+    let formula = match parse(expr) {
+        Ok(formula) => formula,
+        Err(_) => return true,
+    };
+    formula.variables().iter().all(|variable| {
+        reference_region(&resolve_name(variable))
+            .is_none_or(|region| access.can_read_region(&region))
+    })
+}
+
+// Whether the access may read what the formulas of pasted cells refer to once they are moved
+fn can_read_pasted(cells: &[PastedCell], access: &Access) -> bool {
+    cells
+        .iter()
+        .all(|(from, to)| match database_get_value(from).dependency {
+            Some(expr) => {
+                let offset = (to.0 as i64 - from.0 as i64, to.1 as i64 - from.1 as i64);
+                let expr = rewrite_words(&expr, |word| offset_reference(word, offset));
+                can_read_formula(&expr, access)
+            }
+            None => true,
+        })
+}
+
+// Each cell of a range with where it is pasted, the top left corner going to `dest`
+// `None` if cells would be pasted past the last row or column
+fn paste_positions(cells: &[(u32, u32)], dest: (u32, u32)) -> Option<Vec<PastedCell>> {
+    let left = cells.iter().map(|cell| cell.0).min().unwrap_or(0);
+    let top = cells.iter().map(|cell| cell.1).min().unwrap_or(0);
+    cells
+        .iter()
+        .map(|&cell| {
+            let col = (cell.0 - left).checked_add(dest.0)?;
+            let row = (cell.1 - top).checked_add(dest.1)?;
+            Some((cell, (col, row)))
+        })
+        .collect()
+}
+
+// Region of a range that copy, move or fill may paste, `None` if it is not a range
+// or too large, which their handlers report
+fn pasted_region(range: &str) -> Option<Region> {
+    reference_region(&resolve_name(range)).filter(|region| region.cell_count() <= MAX_RANGE_CELLS)
+}
+
+// Error for a range with more cells than a command may change at once
+fn too_large(range: &str) -> Response {
+    Response::Error(format!(
+        "Error: Range too large: {}, more than {} cells",
+        range, MAX_RANGE_CELLS
+    ))
+}
+
+// Whether a command only reads the spreadsheet, never sending a request to the engine
+pub fn is_read(input: &str) -> bool {
+    let words: Vec<_> = input.split_whitespace().take(3).collect();
+    matches!(
+        words.as_slice(),
+        ["get", ..] | ["audit", ..] | ["name", "list"] | ["table", "list"]
+    )
+}
+
 pub fn parse_command(input: &str) -> Command {
     let parts: Vec<&str> = input.splitn(2, ' ').collect();
-    match parts.as_slice() {
+    let command = match parts.as_slice() {
         ["set", args] => Command::Set(args.to_string()),
         ["get", args] => Command::Get(args.to_string()),
+        ["insert", args] => Command::Insert(args.to_string()),
+        ["delete", args] => Command::Delete(args.to_string()),
+        ["copy", args] => Command::Copy(args.to_string()),
+        ["move", args] => Command::Move(args.to_string()),
+        ["fill", args] => Command::Fill(args.to_string()),
+        ["name", args] => Command::Name(args.to_string()),
+        ["table", args] => Command::Table(args.to_string()),
+        ["format", args] => Command::Format(args.to_string()),
+        ["protect", args] => Command::Protect(args.to_string()),
+        ["unprotect", args] => Command::Unprotect(args.to_string()),
+        ["audit", args] => Command::Audit(args.to_string()),
         _ => Command::Unsupported,
+    };
+    record_command(command.name());
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_are_told_apart_from_changes() {
+        assert!(is_read("get A1"));
+        assert!(is_read("get --formatted A1"));
+        assert!(is_read("audit A1"));
+        assert!(is_read("name list"));
+        assert!(is_read("table list"));
+        assert!(!is_read("set A1 get"));
+        assert!(!is_read("name define Total A1_A3"));
+        assert!(!is_read("table create Orders A1_C5"));
+        assert!(!is_read("getter A1"));
+    }
+
+    #[test]
+    fn large_ranges_are_refused_without_listing_their_cells() {
+        // Nothing reaches the engine, the receiver is dropped
+        let (tx, _) = mpsc::channel();
+        let session = Session::anonymous(0);
+        for command in [
+            "format A1_ZZZZZZ4294967295 percent:2",
+            "protect A1_ZZZZZZ4294967295",
+            "copy A1_ZZZZZZ4294967295 B1",
+            "move A1_ZZ4000000 B1",
+            "fill A1 B1_B2000000",
+        ] {
+            match parse_command(command).execute(&tx, &session) {
+                Some(Response::Error(e)) => {
+                    assert!(e.starts_with("Error: Range too large"), "{}", e)
+                }
+                _ => panic!("{} was not refused", command),
+            }
+        }
+    }
+
+    #[test]
+    fn ranges_are_permitted_region_by_region() {
+        let full = Access::full();
+        let nothing = Access::default();
+        let format = parse_command("format A1_ZZZZZZ4294967295 percent:2");
+        assert!(format.is_permitted(&full));
+        assert!(!format.is_permitted(&nothing));
+        assert!(!parse_command("protect A_A").is_permitted(&nothing));
+        assert!(!parse_command("copy A1_B2 C1").is_permitted(&nothing));
+        assert!(parse_command("copy A1_B2 C1").is_permitted(&full));
+    }
+
+    #[test]
+    fn paste_stays_on_the_sheet() {
+        let cells = [(0, 1), (1, 1), (0, 2), (1, 2)];
+        assert_eq!(
+            paste_positions(&cells, (4, 6)),
+            Some(vec![
+                ((0, 1), (4, 6)),
+                ((1, 1), (5, 6)),
+                ((0, 2), (4, 7)),
+                ((1, 2), (5, 7))
+            ])
+        );
+        assert_eq!(paste_positions(&cells, (u32::MAX, 1)), None);
+        assert_eq!(paste_positions(&cells, (1, u32::MAX)), None);
+        assert!(paste_positions(&cells[..1], (u32::MAX, u32::MAX)).is_some());
+    }
+
+    #[test]
+    fn formulas_only_refer_to_readable_cells() {
+        let nothing = Access::default();
+        assert!(can_read_formula("1 + 2", &nothing));
+        assert!(can_read_formula("SUM(1, 2)", &nothing));
+        assert!(!can_read_formula("A1 + 1", &nothing));
+        assert!(!can_read_formula("SUM(B1_B3)", &nothing));
+        assert!(!can_read_formula("A_A[0]", &nothing));
+        assert!(can_read_formula("A1 +", &nothing));
+        assert!(can_read_formula("SUM(B1_B3)", &Access::full()));
     }
 }
@@ -1,19 +1,615 @@
-use crate::utils::command::parse_command;
+use crate::utils::auth::{Auth, Session};
+use crate::utils::command::{parse_command, Response};
 use crate::utils::engine::Transaction;
-use rsheet_lib::connect::{Reader, Writer};
+use crate::utils::json_protocol;
+use crate::utils::metrics::open_connection;
+use crate::utils::value::CellValue;
+use rsheet_lib::connect::{
+    ConnectionError, ConnectionWriter, Manager, Reader, ReaderWriter, TerminalWriter, Writer,
+};
+use rsheet_lib::replies::Reply;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection};
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
 
-pub fn dispatch_commands(
+// Longest message accepted on a TCP connection, a JSON batch can hold many commands
+const MAX_MESSAGE_LENGTH: u64 = 64 * 1024;
+
+// Most pipelined requests running at once on a connection, reading waits beyond that
+const MAX_IN_FLIGHT: usize = 256;
+
+// Line that switches a connection from the text protocol to the JSON-lines protocol
+const JSON_PROTOCOL: &str = "protocol json";
+
+// Command starting a connection when authentication is configured, `login <token>` or
+// `login <user> <password>`, sent before switching protocol
+const LOGIN: &str = "login";
+
+// Number given to the next connection, across every front-end
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+// Writer of a connection that may also send lines of its own, for protocols other than `Reply`s
+// Writers that only send `Reply`s keep the default, and are not offered the JSON-lines protocol
+pub trait LineWriter: Writer {
+    // Send a line as it is, `None` if the writer cannot
+    fn write_line(&mut self, _line: &str) -> Option<Result<(), ConnectionError>> {
+        None
+    }
+}
+
+impl LineWriter for TerminalWriter {
+    fn write_line(&mut self, line: &str) -> Option<Result<(), ConnectionError>> {
+        let mut stdout = io::stdout().lock();
+        let result = writeln!(stdout, "{}", line).and_then(|_| stdout.flush());
+        Some(result.map_err(|_| ConnectionError::ConnectionClosed))
+    }
+}
+
+// `rsheet_lib`'s TCP connections send every message as a `Reply`
+impl LineWriter for ConnectionWriter {}
+
+// Send a line of the JSON-lines protocol, which is only negotiated with writers sending lines
+fn send_line<W: LineWriter>(send: &mut W, line: &str) -> Result<(), ConnectionError> {
+    send.write_line(line)
+        .unwrap_or(Err(ConnectionError::CouldNotConvertToJson))
+}
+
+// Protocol spoken on a connection, every connection starts with the text protocol
+enum Protocol {
+    Text,
+    Json,
+}
+
+// Number identifying a new connection in sessions and the audit log
+pub fn next_connection_id() -> u64 {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub fn dispatch_commands<W: LineWriter + Send + 'static>(
     mut recv: impl Reader,
-    mut send: impl Writer,
+    send: W,
     transactions_sender: mpsc::Sender<Transaction>,
+    auth: Option<Arc<Auth>>,
+    connection: u64,
 ) {
+    let _open = open_connection();
+    let send = Arc::new(Mutex::new(send));
+    let in_flight = Arc::new(InFlight::default());
+    let mut protocol = Protocol::Text;
+    // Connections get full access without authentication, and none until they log in with it
+    let mut session = match auth {
+        Some(_) => None,
+        None => Some(Session::anonymous(connection)),
+    };
     while let Ok(msg) = recv.read_message() {
-        let command = parse_command(&msg);
-        if let Some(response) = command.execute(&transactions_sender) {
-            if send.write_message(response).is_err() {
+        if let (Protocol::Text, Some(args)) = (&protocol, login_args(&msg)) {
+            let response = login(auth.as_deref(), args, connection, &mut session);
+            if send
+                .lock()
+                .unwrap()
+                .write_message(response.into_reply())
+                .is_err()
+            {
                 break;
+            }
+            continue;
+        }
+
+        let result = match (&protocol, &session) {
+            (_, None) => {
+                let reply = Reply::Error(String::from("Error: Not logged in, send `login` first"));
+                send.lock().unwrap().write_message(reply)
+            }
+            (Protocol::Text, _) if msg.trim() == JSON_PROTOCOL => {
+                let mut send = send.lock().unwrap();
+                match send.write_line(&json_protocol::negotiated()) {
+                    Some(result) => {
+                        protocol = Protocol::Json;
+                        result
+                    }
+                    None => send.write_message(Reply::Error(String::from(
+                        "Error: The JSON protocol is not supported on this connection",
+                    ))),
+                }
+            }
+            (Protocol::Text, Some(session)) => {
+                match parse_command(&msg).execute(&transactions_sender, session) {
+                    Some(response) => send.lock().unwrap().write_message(response.into_reply()),
+                    None => Ok(()),
+                }
+            }
+            (Protocol::Json, Some(session)) if json_protocol::is_pipelined(&msg) => {
+                spawn_pipelined(msg, &send, &transactions_sender, session, &in_flight);
+                Ok(())
+            }
+            (Protocol::Json, Some(session)) => {
+                let reply = json_protocol::handle_message(&msg, &transactions_sender, session);
+                send_line(&mut *send.lock().unwrap(), &reply)
+            }
+        };
+        if result.is_err() {
+            break;
+        }
+    }
+
+    // Replies to pipelined requests are still sent after the connection stops reading
+    in_flight.wait_idle();
+}
+
+// Arguments of a `login` message
+fn login_args(msg: &str) -> Option<&str> {
+    match msg.trim().split_once(' ') {
+        Some((LOGIN, args)) => Some(args),
+        None if msg.trim() == LOGIN => Some(""),
+        _ => None,
+    }
+}
+
+// Log a connection in, replacing the session it had
+fn login(
+    auth: Option<&Auth>,
+    args: &str,
+    connection: u64,
+    session: &mut Option<Session>,
+) -> Response {
+    let auth = match auth {
+        Some(auth) => auth,
+        None => return Response::Error(String::from("Error: Login is not required")),
+    };
+    match auth.login(args) {
+        Some((user, access)) => {
+            *session = Some(Session {
+                connection,
+                user: Some(user.clone()),
+                access,
+            });
+            Response::Value(String::from(LOGIN), CellValue::String(user))
+        }
+        None => Response::Error(String::from("Error: Login failed")),
+    }
+}
+
+// Run a pipelined request on a thread of its own, which replies once the request completes
+// The request's transaction is forwarded to the engine before the next message is read,
+// so that changes are applied in the order the client sent them, and reads see earlier changes
+fn spawn_pipelined<W: LineWriter + Send + 'static>(
+    msg: String,
+    send: &Arc<Mutex<W>>,
+    transactions_sender: &mpsc::Sender<Transaction>,
+    session: &Session,
+    in_flight: &Arc<InFlight>,
+) {
+    in_flight.start();
+    let (forward_tx, forward_rx) = mpsc::channel();
+    let send = Arc::clone(send);
+    let session = session.clone();
+    let in_flight = Arc::clone(in_flight);
+    thread::spawn(move || {
+        let reply = json_protocol::handle_pipelined(&msg, &forward_tx, &session);
+        drop(forward_tx);
+        let _ = send_line(&mut *send.lock().unwrap(), &reply);
+        in_flight.finish();
+    });
+
+    // A single request sends one transaction at most, a read the one waiting for earlier changes
+    if let Ok(transaction) = forward_rx.recv() {
+        transactions_sender.send(transaction).unwrap();
+    }
+}
+
+// Number of pipelined requests of a connection that have not replied yet
+#[derive(Default)]
+struct InFlight {
+    count: Mutex<usize>,
+    changed: Condvar,
+}
+
+impl InFlight {
+    fn start(&self) {
+        let mut count = self.count.lock().unwrap();
+        while *count >= MAX_IN_FLIGHT {
+            count = self.changed.wait(count).unwrap();
+        }
+        *count += 1;
+    }
+
+    fn finish(&self) {
+        *self.count.lock().unwrap() -= 1;
+        self.changed.notify_all();
+    }
+
+    fn wait_idle(&self) {
+        let mut count = self.count.lock().unwrap();
+        while *count > 0 {
+            count = self.changed.wait(count).unwrap();
+        }
+    }
+}
+
+// Listener for connections over plain TCP
+// Messages are lines of text and replies are sent as JSON, as `rsheet_lib` does
+pub struct TcpManager {
+    listener: TcpListener,
+}
+
+impl TcpManager {
+    pub fn launch(addr: SocketAddr) -> io::Result<Self> {
+        Ok(TcpManager {
+            listener: TcpListener::bind(addr)?,
+        })
+    }
+}
+
+pub struct TcpReaderWriter;
+
+impl ReaderWriter for TcpReaderWriter {
+    type Reader = TcpReader;
+    type Writer = TcpWriter;
+}
+
+impl Manager for TcpManager {
+    type ReaderWriter = TcpReaderWriter;
+
+    fn accept_new_connection(&mut self) -> Result<(TcpReader, TcpWriter), ()> {
+        let (socket, addr) = self.listener.accept().map_err(|_| ())?;
+        let reader = TcpReader {
+            reader: BufReader::new(socket.try_clone().map_err(|_| ())?),
+            addr,
+        };
+        let writer = TcpWriter { socket, addr };
+        Ok((reader, writer))
+    }
+}
+
+pub struct TcpReader {
+    reader: BufReader<TcpStream>,
+    addr: SocketAddr,
+}
+
+pub struct TcpWriter {
+    socket: TcpStream,
+    addr: SocketAddr,
+}
+
+impl Reader for TcpReader {
+    fn read_message(&mut self) -> Result<String, ConnectionError> {
+        let mut bytes = Vec::new();
+        let read = (&mut self.reader)
+            .take(MAX_MESSAGE_LENGTH)
+            .read_until(b'\n', &mut bytes)
+            .map_err(|_| ConnectionError::ConnectionLost)?;
+        if read == 0 {
+            return Err(ConnectionError::ConnectionClosed);
+        }
+        if bytes.last() != Some(&b'\n') && read as u64 == MAX_MESSAGE_LENGTH {
+            return Err(ConnectionError::MessageTooLong);
+        }
+
+        let message = String::from_utf8(bytes).map_err(|_| ConnectionError::MessageInvalidUtf8)?;
+        Ok(message.trim_end_matches(['\n', '\r']).to_string())
+    }
+
+    fn id(&self) -> String {
+        self.addr.to_string()
+    }
+}
+
+impl Writer for TcpWriter {
+    fn write_message(&mut self, message: Reply) -> Result<(), ConnectionError> {
+        let message =
+            serde_json::to_string(&message).map_err(|_| ConnectionError::CouldNotConvertToJson)?;
+        self.write_text(&message)
+    }
+
+    fn id(&self) -> String {
+        self.addr.to_string()
+    }
+}
+
+impl LineWriter for TcpWriter {
+    fn write_line(&mut self, line: &str) -> Option<Result<(), ConnectionError>> {
+        Some(self.write_text(line))
+    }
+}
+
+impl TcpWriter {
+    fn write_text(&mut self, line: &str) -> Result<(), ConnectionError> {
+        self.socket
+            .write_all(format!("{}\n", line).as_bytes())
+            .map_err(|_| ConnectionError::ConnectionClosed)?;
+        let _ = self.socket.flush();
+        Ok(())
+    }
+}
+
+// Listener for connections over TLS, speaking the same lines as `TcpManager` once decrypted
+// The handshake happens as the connection is first read, so a slow client never holds up others
+pub struct TlsManager {
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+}
+
+impl TlsManager {
+    // Serve the certificate chain and private key of PEM files
+    pub fn launch(
+        addr: SocketAddr,
+        cert_path: &Path,
+        key_path: &Path,
+    ) -> Result<Self, Box<dyn Error>> {
+        let in_file = |path: &Path, e: &dyn Error| format!("{}: {}", path.display(), e);
+        let certs = CertificateDer::pem_file_iter(cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| in_file(cert_path, &e))?;
+        let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| in_file(key_path, &e))?;
+        let config =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()?
+                .with_no_client_auth()
+                .with_single_cert(certs, key)?;
+        Ok(TlsManager {
+            listener: TcpListener::bind(addr)?,
+            config: Arc::new(config),
+        })
+    }
+}
+
+pub struct TlsReaderWriter;
+
+impl ReaderWriter for TlsReaderWriter {
+    type Reader = TlsReader;
+    type Writer = TlsWriter;
+}
+
+impl Manager for TlsManager {
+    type ReaderWriter = TlsReaderWriter;
+
+    fn accept_new_connection(&mut self) -> Result<(TlsReader, TlsWriter), ()> {
+        let (socket, addr) = self.listener.accept().map_err(|_| ())?;
+        let session = ServerConnection::new(Arc::clone(&self.config)).map_err(|_| ())?;
+        let session = Arc::new(Mutex::new(session));
+        let reader = TlsReader {
+            socket: socket.try_clone().map_err(|_| ())?,
+            session: Arc::clone(&session),
+            plaintext: Vec::new(),
+            addr,
+        };
+        let writer = TlsWriter {
+            socket,
+            session,
+            addr,
+        };
+        Ok((reader, writer))
+    }
+}
+
+// The reader and the writer of a connection share its TLS session, each with its own handle
+// on the socket, so that reading never waits with the session locked
+pub struct TlsReader {
+    socket: TcpStream,
+    session: Arc<Mutex<ServerConnection>>,
+    // Decrypted bytes not yet returned as a message
+    plaintext: Vec<u8>,
+    addr: SocketAddr,
+}
+
+pub struct TlsWriter {
+    socket: TcpStream,
+    session: Arc<Mutex<ServerConnection>>,
+    addr: SocketAddr,
+}
+
+// Send the records the session has ready, handshake messages and alerts as well as data
+fn flush_tls(
+    session: &mut ServerConnection,
+    mut socket: &TcpStream,
+) -> Result<(), ConnectionError> {
+    while session.wants_write() {
+        session
+            .write_tls(&mut socket)
+            .map_err(|_| ConnectionError::ConnectionClosed)?;
+    }
+    Ok(())
+}
+
+impl TlsReader {
+    // Decrypt the next records from the socket, false once the client closed the connection
+    fn read_plaintext(&mut self) -> Result<bool, ConnectionError> {
+        let mut records = [0; 4096];
+        let read = loop {
+            match self.socket.read(&mut records) {
+                Ok(read) => break read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return Err(ConnectionError::ConnectionLost),
+            }
+        };
+        if read == 0 {
+            return Ok(false);
+        }
+
+        let mut session = self.session.lock().unwrap();
+        let mut records = &records[..read];
+        let mut open = true;
+        while !records.is_empty() {
+            session
+                .read_tls(&mut records)
+                .map_err(|_| ConnectionError::ConnectionLost)?;
+            let state = match session.process_new_packets() {
+                Ok(state) => state,
+                Err(_) => {
+                    // Tell the client why with the alert the session queued
+                    let _ = flush_tls(&mut session, &self.socket);
+                    return Err(ConnectionError::ConnectionLost);
+                }
             };
+            let start = self.plaintext.len();
+            self.plaintext
+                .resize(start + state.plaintext_bytes_to_read(), 0);
+            session
+                .reader()
+                .read_exact(&mut self.plaintext[start..])
+                .map_err(|_| ConnectionError::ConnectionLost)?;
+            open = !state.peer_has_closed();
         }
+        flush_tls(&mut session, &self.socket)?;
+        Ok(open)
+    }
+}
+
+impl Reader for TlsReader {
+    fn read_message(&mut self) -> Result<String, ConnectionError> {
+        loop {
+            if let Some(end) = self.plaintext.iter().position(|&byte| byte == b'\n') {
+                let bytes: Vec<_> = self.plaintext.drain(..=end).collect();
+                let message =
+                    String::from_utf8(bytes).map_err(|_| ConnectionError::MessageInvalidUtf8)?;
+                return Ok(message.trim_end_matches(['\n', '\r']).to_string());
+            }
+            if self.plaintext.len() as u64 >= MAX_MESSAGE_LENGTH {
+                return Err(ConnectionError::MessageTooLong);
+            }
+            if !self.read_plaintext()? {
+                return Err(ConnectionError::ConnectionClosed);
+            }
+        }
+    }
+
+    fn id(&self) -> String {
+        self.addr.to_string()
+    }
+}
+
+impl Writer for TlsWriter {
+    fn write_message(&mut self, message: Reply) -> Result<(), ConnectionError> {
+        let message =
+            serde_json::to_string(&message).map_err(|_| ConnectionError::CouldNotConvertToJson)?;
+        self.write_text(&message)
+    }
+
+    fn id(&self) -> String {
+        self.addr.to_string()
+    }
+}
+
+impl LineWriter for TlsWriter {
+    fn write_line(&mut self, line: &str) -> Option<Result<(), ConnectionError>> {
+        Some(self.write_text(line))
+    }
+}
+
+impl TlsWriter {
+    fn write_text(&mut self, line: &str) -> Result<(), ConnectionError> {
+        let mut session = self.session.lock().unwrap();
+        session
+            .writer()
+            .write_all(format!("{}\n", line).as_bytes())
+            .map_err(|_| ConnectionError::ConnectionClosed)?;
+        flush_tls(&mut session, &self.socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+    use std::fs;
+
+    // Tests run at the same time, each writes its certificate to its own directory
+    static LAUNCHED: AtomicU64 = AtomicU64::new(0);
+
+    // A manager serving a certificate for `localhost`, and a client trusting only that certificate
+    fn launch_tls() -> (TlsManager, Arc<ClientConfig>) {
+        let certified =
+            rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let dir = std::env::temp_dir().join(format!(
+            "rsheet-tls-{}-{}",
+            std::process::id(),
+            LAUNCHED.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&cert_path, certified.cert.pem()).unwrap();
+        fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+        let manager =
+            TlsManager::launch("127.0.0.1:0".parse().unwrap(), &cert_path, &key_path).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        let config =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        (manager, Arc::new(config))
+    }
+
+    fn connect(
+        manager: &TlsManager,
+        config: Arc<ClientConfig>,
+    ) -> BufReader<StreamOwned<ClientConnection, TcpStream>> {
+        let socket = TcpStream::connect(manager.listener.local_addr().unwrap()).unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        let session = ClientConnection::new(config, name).unwrap();
+        BufReader::new(StreamOwned::new(session, socket))
+    }
+
+    #[test]
+    fn lines_over_tls() {
+        let (mut manager, config) = launch_tls();
+        let mut client = connect(&manager, config);
+        let server = thread::spawn(move || {
+            let (mut reader, mut writer) = manager.accept_new_connection().unwrap();
+            let messages: Vec<_> = (0..3).map(|_| reader.read_message().unwrap()).collect();
+            writer
+                .write_message(Reply::Error(String::from("Error: nope")))
+                .unwrap();
+            writer.write_line("{\"id\":1}").unwrap().unwrap();
+            (messages, reader.read_message())
+        });
+
+        // Two lines in one record, then a line split across records
+        client
+            .get_mut()
+            .write_all(b"get A1\r\nget B2\nset ")
+            .unwrap();
+        client.get_mut().flush().unwrap();
+        client.get_mut().write_all(b"C3 4\n").unwrap();
+        client.get_mut().flush().unwrap();
+
+        let mut reply = String::new();
+        client.read_line(&mut reply).unwrap();
+        assert_eq!(reply, "{\"Error\":\"Error: nope\"}\n");
+        reply.clear();
+        client.read_line(&mut reply).unwrap();
+        assert_eq!(reply, "{\"id\":1}\n");
+
+        client.get_mut().conn.send_close_notify();
+        client.get_mut().flush().unwrap();
+        let (messages, closed) = server.join().unwrap();
+        assert_eq!(messages, ["get A1", "get B2", "set C3 4"]);
+        assert!(matches!(closed, Err(ConnectionError::ConnectionClosed)));
+    }
+
+    #[test]
+    fn connection_closed_when_the_client_rejects_the_certificate() {
+        let (mut manager, _) = launch_tls();
+        // A client trusting another certificate
+        let (_, other) = launch_tls();
+        let mut client = connect(&manager, other);
+        let server = thread::spawn(move || {
+            let (mut reader, _writer) = manager.accept_new_connection().unwrap();
+            reader.read_message()
+        });
+        assert!(client.get_mut().write_all(b"get A1\n").is_err());
+        assert!(server.join().unwrap().is_err());
     }
 }
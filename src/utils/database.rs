@@ -1,13 +1,26 @@
+use crate::utils::cell_format::CellFormat;
+use crate::utils::value::{CellArgument, CellValue};
 use dashmap::DashMap;
 use lazy_static::lazy_static;
 use rsheet_lib::cells::{column_name_to_number, column_number_to_name};
-use rsheet_lib::command_runner::{CellArgument, CellValue};
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+
+use crate::utils::formula::REFERENCE_ERROR;
+use crate::utils::parser::Expr;
 
 // Storing cell values and dependencies
+// Formulas are kept both as text and parsed, so recalculation never parses them again
+// The display format belongs to the cell and stays when its contents are replaced
 #[derive(Clone)]
 pub struct CellRef {
     pub(crate) cell_value: CellValue,
     pub(crate) dependency: Option<String>,
+    pub(crate) formula: Option<Arc<Expr>>,
+    pub(crate) format: Option<CellFormat>,
+    // Protected cells keep their contents until they are unprotected
+    pub(crate) protected: bool,
 }
 
 impl CellRef {
@@ -15,6 +28,19 @@ impl CellRef {
         CellRef {
             cell_value,
             dependency,
+            formula: None,
+            format: None,
+            protected: false,
+        }
+    }
+
+    pub fn with_formula(cell_value: CellValue, dependency: String, formula: Expr) -> Self {
+        CellRef {
+            cell_value,
+            dependency: Some(dependency),
+            formula: Some(Arc::new(formula)),
+            format: None,
+            protected: false,
         }
     }
 }
@@ -25,6 +51,17 @@ lazy_static! {
     static ref DATABASE: DashMap<(u32, u32), CellRef> = DashMap::new();
 }
 
+// Defined names, mapping each name to the cell or range variable it stands for
+lazy_static! {
+    static ref NAMES: DashMap<String, String> = DashMap::new();
+}
+
+// Tables, mapping each table name to the range it covers
+// The top row of a table holds the headers of its columns
+lazy_static! {
+    static ref TABLES: DashMap<String, String> = DashMap::new();
+}
+
 pub fn database_get_value(key: &(u32, u32)) -> CellRef {
     DATABASE
         .get(key)
@@ -32,102 +69,783 @@ pub fn database_get_value(key: &(u32, u32)) -> CellRef {
         .unwrap_or(CellRef::new(CellValue::None, None))
 }
 
-pub fn database_insert(key: (u32, u32), value: CellRef) -> Option<CellRef> {
+// Store a cell, keeping the display format and protection of the cell it replaces
+pub fn database_insert(key: (u32, u32), mut value: CellRef) -> Option<CellRef> {
+    if let Some(entry) = DATABASE.get(&key) {
+        if value.format.is_none() {
+            value.format = entry.format.clone();
+        }
+        value.protected |= entry.protected;
+    }
     DATABASE.insert(key, value)
 }
 
-// Parsing Cell Addresses
-pub fn split_cell_id(cell_id: &str) -> Option<(u32, u32)> {
-    let mut split_index = 0;
-    for (index, ch) in cell_id.chars().enumerate() {
-        if ch.is_ascii_digit() {
-            split_index = index;
-            break;
-        }
+// Attach a display format to a cell, or remove it with `None`
+pub fn database_set_format(key: (u32, u32), format: Option<CellFormat>) {
+    DATABASE
+        .entry(key)
+        .or_insert_with(|| CellRef::new(CellValue::None, None))
+        .format = format;
+}
+
+// Mark a cell as protected or not
+pub fn database_set_protected(key: (u32, u32), protected: bool) {
+    DATABASE
+        .entry(key)
+        .or_insert_with(|| CellRef::new(CellValue::None, None))
+        .protected = protected;
+}
+
+// Positions of every protected cell
+pub fn database_protected() -> Vec<(u32, u32)> {
+    DATABASE
+        .iter()
+        .filter(|entry| entry.protected)
+        .map(|entry| *entry.key())
+        .collect()
+}
+
+// Replace the value of a cell, keeping its formula
+pub fn database_set_value(key: &(u32, u32), cell_value: CellValue) {
+    if let Some(mut cell_ref) = DATABASE.get_mut(key) {
+        cell_ref.cell_value = cell_value;
     }
-    if split_index == 0 {
-        None
-    } else {
-        let (col, row) = cell_id.split_at(split_index);
-        row.parse::<u32>()
-            .ok()
-            .map(|row| (column_name_to_number(col), row))
+}
+
+pub fn names_get(name: &str) -> Option<String> {
+    NAMES.get(name).map(|entry| entry.clone())
+}
+
+pub fn names_insert(name: String, reference: String) -> Option<String> {
+    NAMES.insert(name, reference)
+}
+
+pub fn names_remove(name: &str) -> Option<String> {
+    NAMES.remove(name).map(|(_, reference)| reference)
+}
+
+// All defined names and their references, sorted by name
+pub fn names_list() -> Vec<(String, String)> {
+    let mut names: Vec<_> = NAMES
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().clone()))
+        .collect();
+    names.sort();
+    names
+}
+
+pub fn tables_get(table: &str) -> Option<String> {
+    TABLES.get(table).map(|entry| entry.clone())
+}
+
+pub fn tables_insert(table: String, reference: String) -> Option<String> {
+    TABLES.insert(table, reference)
+}
+
+// All tables and their ranges, sorted by name
+pub fn tables_list() -> Vec<(String, String)> {
+    let mut tables: Vec<_> = TABLES
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().clone()))
+        .collect();
+    tables.sort();
+    tables
+}
+
+// Top left and bottom right cells of a table
+pub fn table_bounds(table: &str) -> Option<((u32, u32), (u32, u32))> {
+    reference_region(&tables_get(table)?)?.resolve()
+}
+
+// Split a table column variable like `Orders.Amount` into the table and the column header
+pub fn split_table_column(variable: &str) -> Option<(&str, &str)> {
+    variable
+        .split_once('.')
+        .filter(|(table, _)| is_valid_name(table))
+}
+
+// Range variable of the cells below the header of a table column
+// A table whose range was deleted resolves to `REFERENCE_ERROR`
+fn resolve_table_column(variable: &str) -> Option<String> {
+    let (table, header) = split_table_column(variable)?;
+    if tables_get(table)? == REFERENCE_ERROR {
+        return Some(String::from(REFERENCE_ERROR));
     }
+    let ((left, top), (right, bottom)) = table_bounds(table)?;
+    if bottom == top {
+        return None;
+    }
+    let col = (left..=right).find(|col| {
+        database_get_value(&(*col, top)).cell_value == CellValue::String(header.to_string())
+    })?;
+    Some(join_reference(&[
+        CellAddress::new((col, top + 1)),
+        CellAddress::new((col, bottom)),
+    ]))
 }
 
-// Converting the key of a hashmap to a cell address
-pub fn pos_to_cell_id(position: &(u32, u32)) -> String {
-    format!(
-        "{}{}",
-        column_number_to_name(position.0),
-        &position.1.to_string()
-    )
+// Check whether a word can be used as a defined name in formulas
+// Names start with an uppercase letter, contain only letters, digits and `_`,
+// and must not look like a cell or range reference
+pub fn is_valid_name(name: &str) -> bool {
+    name.starts_with(|ch: char| ch.is_ascii_uppercase())
+        && name
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+        && split_reference(name).is_none()
+        && name != REFERENCE_ERROR
+        && name != "TRUE"
+        && name != "FALSE"
 }
 
-// Convert variables to vectors of keys in a hashmap
-pub fn parse_to_indices(range: &str) -> Option<Vec<(u32, u32)>> {
-    let parts: Vec<&str> = range.split('_').collect();
-    let mut result = Vec::new();
-
-    match parts.len() {
-        1 => {
-            if let Some(coords) = split_cell_id(parts[0]) {
-                result.push(coords);
-            } else {
-                return None;
+// Replace a defined name or a table column with the variable it stands for,
+// other variables are kept as they are
+pub fn resolve_name(variable: &str) -> String {
+    names_get(variable)
+        .or_else(|| resolve_table_column(variable))
+        .unwrap_or_else(|| variable.to_string())
+}
+
+// Collect the keys and expressions of all cells that depend on other cells
+pub fn database_formulas() -> Vec<((u32, u32), String)> {
+    DATABASE
+        .iter()
+        .filter_map(|entry| {
+            entry
+                .dependency
+                .as_ref()
+                .map(|expr| (*entry.key(), expr.clone()))
+        })
+        .collect()
+}
+
+// Move every cell to its position after a structural change, returning the deleted cells
+pub fn database_shift(shift: &Shift) -> Vec<((u32, u32), CellRef)> {
+    let moved: Vec<(u32, u32)> = DATABASE
+        .iter()
+        .map(|entry| *entry.key())
+        .filter(|key| shift.apply(key) != Some(*key))
+        .collect();
+
+    // Remove all moving cells first so that no cell overwrites another one
+    let entries: Vec<_> = moved
+        .iter()
+        .filter_map(|key| DATABASE.remove(key))
+        .collect();
+    let mut deleted = Vec::new();
+    for (key, value) in entries {
+        match shift.apply(&key) {
+            Some(new_key) => {
+                DATABASE.insert(new_key, value);
             }
+            None => deleted.push((key, value)),
         }
-        2 => {
-            if let (Some(start), Some(end)) = (split_cell_id(parts[0]), split_cell_id(parts[1])) {
-                for row in start.0..=end.0 {
-                    for col in start.1..=end.1 {
-                        result.push((row, col));
+    }
+    deleted
+}
+
+// Axis of a structural change
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Axis {
+    Row,
+    Col,
+}
+
+// Structural change to the sheet
+// `Insert` places an empty row/column before `index`, `Delete` removes the row/column at `index`
+// Rows are indexed by the number in the cell address, columns are indexed from zero
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Shift {
+    Insert(Axis, u32),
+    Delete(Axis, u32),
+}
+
+impl Shift {
+    fn axis(&self) -> Axis {
+        match self {
+            Shift::Insert(axis, _) | Shift::Delete(axis, _) => *axis,
+        }
+    }
+
+    // New bounds of the interval `start..=end` along the shifted axis, open bounds stay open
+    // Returns `None` if every index of the interval is deleted or pushed off the sheet
+    fn apply_interval(
+        &self,
+        start: Option<u32>,
+        end: Option<u32>,
+    ) -> Option<(Option<u32>, Option<u32>)> {
+        // Reversed intervals are shifted like their normalised form
+        if let (Some(start), Some(end)) = (start, end) {
+            if start > end {
+                return self
+                    .apply_interval(Some(end), Some(start))
+                    .map(|(end, start)| (start, end));
+            }
+        }
+
+        match *self {
+            Shift::Insert(_, index) => {
+                // Indices pushed past the last row or column fall off the sheet
+                let shifted = |i: u32| {
+                    if i >= index {
+                        i.checked_add(1)
+                    } else {
+                        Some(i)
                     }
+                };
+                let start = match start {
+                    Some(start) => Some(shifted(start)?),
+                    None => None,
+                };
+                Some((start, end.map(|end| shifted(end).unwrap_or(u32::MAX))))
+            }
+            Shift::Delete(_, index) => {
+                if start == Some(index) && end == Some(index) {
+                    return None;
                 }
+                Some((
+                    start.map(|start| if start > index { start - 1 } else { start }),
+                    end.map(|end| {
+                        if end >= index {
+                            end.saturating_sub(1)
+                        } else {
+                            end
+                        }
+                    }),
+                ))
             }
         }
-        _ => return None,
     }
-    Some(result)
+
+    // New position of a cell, or `None` if the cell is deleted
+    pub fn apply(&self, position: &(u32, u32)) -> Option<(u32, u32)> {
+        self.apply_region(&Region::cell(*position))
+            .and_then(|region| region.left.zip(region.top))
+    }
+
+    // New bounds of a region, shrinking or growing it with the change
+    // Returns `None` if every cell of the region is deleted
+    pub fn apply_region(&self, region: &Region) -> Option<Region> {
+        match self.axis() {
+            Axis::Row => self
+                .apply_interval(region.top, region.bottom)
+                .map(|(top, bottom)| Region {
+                    top,
+                    bottom,
+                    ..*region
+                }),
+            Axis::Col => self
+                .apply_interval(region.left, region.right)
+                .map(|(left, right)| Region {
+                    left,
+                    right,
+                    ..*region
+                }),
+        }
+    }
+
+    // Rewrite a cell or range variable of a formula so it keeps referring to the same cells
+    // Anchors are kept, references to deleted cells become `REFERENCE_ERROR`
+    // Returns `None` if the variable is not a reference
+    pub fn rewrite_reference(&self, variable: &str) -> Option<String> {
+        let addresses = split_reference(variable)?;
+        let start = addresses.first()?;
+        let end = addresses.last()?;
+        let rewritten = match self.apply_region(&Region::from_corners(start, end)) {
+            Some(region) if addresses.len() == 2 => join_reference(&[
+                start.moved_to(region.left, region.top),
+                end.moved_to(region.right, region.bottom),
+            ]),
+            Some(region) => join_reference(&[start.moved_to(region.left, region.top)]),
+            None => String::from(REFERENCE_ERROR),
+        };
+        Some(rewritten)
+    }
 }
 
-// Scalar Vector Matrix to <CellArgument>
-pub fn get_cell_argument(cell_id: &str) -> Option<CellArgument> {
-    let parts: Vec<&str> = cell_id.split('_').collect();
-    match parts.len() {
-        1 => split_cell_id(parts[0]).and_then(|index| {
-            if let CellValue::Error(_) = database_get_value(&index).cell_value {
-                return None;
+// Cell address as written in a formula
+// The column and the row can each be anchored with `$`, like `$A$1`, `$A1` or `A$1`
+// Anchors only matter when a formula is copied, they never change which cell is referenced
+// A corner of a range may leave out its row (`A`) or its column (`3`),
+// making the range extend over the whole column or row
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CellAddress {
+    pub col: Option<u32>,
+    pub row: Option<u32>,
+    pub col_anchored: bool,
+    pub row_anchored: bool,
+}
+
+impl CellAddress {
+    pub fn new(position: (u32, u32)) -> Self {
+        CellAddress {
+            col: Some(position.0),
+            row: Some(position.1),
+            col_anchored: false,
+            row_anchored: false,
+        }
+    }
+
+    // Parsing `A1`, `$A1`, `A$1` and `$A$1`, as well as the column `A` and the row `3`
+    pub fn parse(cell_id: &str) -> Option<Self> {
+        let (col_anchored, rest) = match cell_id.strip_prefix('$') {
+            Some(rest) => (true, rest),
+            None => (false, cell_id),
+        };
+        let split_index = rest
+            .find(|ch: char| !ch.is_ascii_uppercase())
+            .unwrap_or(rest.len());
+        let (col, rest) = rest.split_at(split_index);
+        let (row_anchored, row) = match rest.strip_prefix('$') {
+            Some(row) => (true, row),
+            None => (false, rest),
+        };
+        if (col.is_empty() && col_anchored) || (row.is_empty() && row_anchored) {
+            return None;
+        }
+        if !row.chars().all(|ch| ch.is_ascii_digit()) {
+            return None;
+        }
+
+        let col = match col {
+            "" => None,
+            col => Some(column_number(col)?),
+        };
+        let row = match row {
+            "" => None,
+            row => Some(row.parse::<u32>().ok()?),
+        };
+        if col.is_none() && row.is_none() {
+            return None;
+        }
+        Some(CellAddress {
+            col,
+            row,
+            col_anchored,
+            row_anchored,
+        })
+    }
+
+    // Position of the cell, `None` for a whole column or row
+    pub fn position(&self) -> Option<(u32, u32)> {
+        self.col.zip(self.row)
+    }
+
+    // The same address at another position, keeping its anchors
+    pub fn moved_to(&self, col: Option<u32>, row: Option<u32>) -> Self {
+        CellAddress { col, row, ..*self }
+    }
+}
+
+impl Display for CellAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(col) = self.col {
+            if self.col_anchored {
+                write!(f, "$")?;
             }
-            Some(CellArgument::Value(database_get_value(&index).cell_value))
-        }),
-        2 => {
-            let start = split_cell_id(parts[0])?;
-            let end = split_cell_id(parts[1])?;
-            if start.0 == end.0 {
-                Some(CellArgument::Vector(
-                    (start.1..=end.1)
-                        .map(|y| database_get_value(&(start.0, y)).cell_value)
-                        .collect(),
-                ))
-            } else if start.1 == end.1 {
-                Some(CellArgument::Vector(
-                    (start.0..=end.0)
-                        .map(|x| database_get_value(&(x, start.1)).cell_value)
-                        .collect(),
-                ))
-            } else {
-                let mut matrix = Vec::new();
-                for x in start.0..=end.0 {
-                    let mut row = Vec::new();
-                    for y in start.1..=end.1 {
-                        row.push(database_get_value(&(x, y)).cell_value);
-                    }
-                    matrix.push(row);
-                }
-                Some(CellArgument::Matrix(matrix))
+            write!(f, "{}", column_number_to_name(col))?;
+        }
+        if let Some(row) = self.row {
+            if self.row_anchored {
+                write!(f, "$")?;
+            }
+            write!(f, "{}", row)?;
+        }
+        Ok(())
+    }
+}
+
+// Rectangle of cells between two corners, inclusive
+// `None` bounds are open and extend to the populated edge of the sheet
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub struct Region {
+    pub left: Option<u32>,
+    pub top: Option<u32>,
+    pub right: Option<u32>,
+    pub bottom: Option<u32>,
+}
+
+impl Region {
+    pub fn cell(position: (u32, u32)) -> Self {
+        Region {
+            left: Some(position.0),
+            top: Some(position.1),
+            right: Some(position.0),
+            bottom: Some(position.1),
+        }
+    }
+
+    pub fn from_corners(start: &CellAddress, end: &CellAddress) -> Self {
+        Region {
+            left: start.col,
+            top: start.row,
+            right: end.col,
+            bottom: end.row,
+        }
+    }
+
+    // Top left and bottom right corners, with open sides at the edge of the sheet
+    pub fn bounds(&self) -> ((u32, u32), (u32, u32)) {
+        let (left, right) = match (self.left, self.right) {
+            (Some(left), Some(right)) => (left.min(right), left.max(right)),
+            (left, right) => (left.unwrap_or(0), right.unwrap_or(u32::MAX)),
+        };
+        let (top, bottom) = match (self.top, self.bottom) {
+            (Some(top), Some(bottom)) => (top.min(bottom), top.max(bottom)),
+            (top, bottom) => (top.unwrap_or(0), bottom.unwrap_or(u32::MAX)),
+        };
+        ((left, top), (right, bottom))
+    }
+
+    pub fn contains(&self, position: &(u32, u32)) -> bool {
+        let (min, max) = self.bounds();
+        (min.0..=max.0).contains(&position.0) && (min.1..=max.1).contains(&position.1)
+    }
+
+    // Close the open sides of the region at the extent of the populated cells inside it
+    // Returns the top left and bottom right cells, whichever corners the region was written with,
+    // or `None` if an open region has no populated cells
+    pub fn resolve(&self) -> Option<((u32, u32), (u32, u32))> {
+        if let (Some(_), Some(_), Some(_), Some(_)) = (self.left, self.top, self.right, self.bottom)
+        {
+            return Some(self.bounds());
+        }
+
+        let mut extent: Option<((u32, u32), (u32, u32))> = None;
+        for entry in DATABASE.iter() {
+            let key = entry.key();
+            if entry.cell_value == CellValue::None && entry.dependency.is_none() {
+                continue;
+            }
+            if !self.contains(key) {
+                continue;
+            }
+            extent = Some(match extent {
+                None => (*key, *key),
+                Some((min, max)) => (
+                    (min.0.min(key.0), min.1.min(key.1)),
+                    (max.0.max(key.0), max.1.max(key.1)),
+                ),
+            });
+        }
+
+        extent.map(|(min, max)| {
+            Region {
+                left: self.left.or(Some(min.0)),
+                top: self.top.or(Some(min.1)),
+                right: self.right.or(Some(max.0)),
+                bottom: self.bottom.or(Some(max.1)),
             }
+            .bounds()
+        })
+    }
+
+    // Number of cells of the region once its open sides are closed, as `rows` lists them
+    pub fn cell_count(&self) -> u64 {
+        match self.resolve() {
+            Some(((left, top), (right, bottom))) => {
+                (right as u64 - left as u64 + 1).saturating_mul(bottom as u64 - top as u64 + 1)
+            }
+            None => 0,
         }
+    }
+
+    // Cells of the region as rows of cells, from the top row to the bottom row,
+    // each row from the leftmost to the rightmost column
+    // This is the layout of every `CellArgument::Matrix`
+    pub fn rows(&self) -> Vec<Vec<(u32, u32)>> {
+        match self.resolve() {
+            Some(((left, top), (right, bottom))) => (top..=bottom)
+                .map(|row| (left..=right).map(|col| (col, row)).collect())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+// Most cells a command or request may list at once, larger ranges are rejected
+// before their cells are listed
+pub const MAX_RANGE_CELLS: u64 = 1_000_000;
+
+// Longest column name accepted, longer names would overflow the column number
+const MAX_COLUMN_LETTERS: usize = 6;
+
+// Number of a column name like `A` or `AB`, `None` if it is not one or is too long
+pub fn column_number(name: &str) -> Option<u32> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_COLUMN_LETTERS
+        && name.bytes().all(|byte| byte.is_ascii_uppercase());
+    valid.then(|| column_name_to_number(name))
+}
+
+// Parsing Cell Addresses
+pub fn split_cell_id(cell_id: &str) -> Option<(u32, u32)> {
+    CellAddress::parse(cell_id).and_then(|address| address.position())
+}
+
+// Converting the key of a hashmap to a cell address
+pub fn pos_to_cell_id(position: &(u32, u32)) -> String {
+    CellAddress::new(*position).to_string()
+}
+
+// Split a cell variable `A1` or a range variable like `A1_B5`, `A_A`, `3_3` or `A2_A`
+// into the addresses of its corners
+pub fn split_reference(variable: &str) -> Option<Vec<CellAddress>> {
+    // The marker of a deleted reference would otherwise read as the range of columns REF to ERROR
+    if variable == REFERENCE_ERROR {
+        return None;
+    }
+    let addresses = variable
+        .split('_')
+        .map(CellAddress::parse)
+        .collect::<Option<Vec<_>>>()?;
+    match addresses.as_slice() {
+        [cell] if cell.position().is_some() => Some(addresses),
+        [_, _] => Some(addresses),
         _ => None,
     }
 }
+
+// Converting the corners of a cell or range back into a variable
+pub fn join_reference(addresses: &[CellAddress]) -> String {
+    addresses
+        .iter()
+        .map(|address| address.to_string())
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+// Region covered by a cell or range variable
+pub fn reference_region(variable: &str) -> Option<Region> {
+    let addresses = split_reference(variable)?;
+    Some(Region::from_corners(addresses.first()?, addresses.last()?))
+}
+
+// Move a cell or range variable of a formula by `offset` (columns, rows),
+// as when the formula is copied to another cell; anchored and open parts stay in place
+// References moved off the sheet become `REFERENCE_ERROR`
+// Returns `None` if the variable is not a reference
+pub fn offset_reference(variable: &str, offset: (i64, i64)) -> Option<String> {
+    let mut rewritten = Vec::new();
+    for address in split_reference(variable)? {
+        let col = match address.col {
+            Some(col) if !address.col_anchored => match u32::try_from(col as i64 + offset.0) {
+                Ok(col) => Some(col),
+                Err(_) => return Some(String::from(REFERENCE_ERROR)),
+            },
+            col => col,
+        };
+        let row = match address.row {
+            Some(row) if !address.row_anchored => match u32::try_from(row as i64 + offset.1) {
+                Ok(row) => Some(row),
+                Err(_) => return Some(String::from(REFERENCE_ERROR)),
+            },
+            row => row,
+        };
+        rewritten.push(address.moved_to(col, row));
+    }
+    Some(join_reference(&rewritten))
+}
+
+// Point a cell or range variable at where its cells were moved, `moved` giving the new position
+// of each moved cell; anchors are kept, as moving never changes what a reference means
+// A range only follows when every cell of it was moved
+// Returns `None` if the variable is not a reference or does not follow the move
+pub fn move_reference(variable: &str, moved: &HashMap<(u32, u32), (u32, u32)>) -> Option<String> {
+    let addresses = split_reference(variable)?;
+    let start = addresses.first()?;
+    let end = addresses.last()?;
+    let ((left, top), (right, bottom)) = Region::from_corners(start, end).bounds();
+    let area = (right as u64 - left as u64 + 1).saturating_mul(bottom as u64 - top as u64 + 1);
+    if area > moved.len() as u64 {
+        return None;
+    }
+    let all_moved =
+        (left..=right).all(|col| (top..=bottom).all(|row| moved.contains_key(&(col, row))));
+    if !all_moved {
+        return None;
+    }
+    let rewritten: Vec<_> = addresses
+        .iter()
+        .map(|address| {
+            let position = moved[&address.position()?];
+            Some(address.moved_to(Some(position.0), Some(position.1)))
+        })
+        .collect::<Option<_>>()?;
+    Some(join_reference(&rewritten))
+}
+
+// Whether a range, name or table column has more cells than `MAX_RANGE_CELLS`,
+// found without listing them
+pub fn range_too_large(range: &str) -> bool {
+    reference_region(&resolve_name(range))
+        .is_some_and(|region| region.cell_count() > MAX_RANGE_CELLS)
+}
+
+// Convert variables to vectors of keys in a hashmap, row by row
+pub fn parse_to_indices(range: &str) -> Option<Vec<(u32, u32)>> {
+    Some(parse_to_rows(range)?.concat())
+}
+
+// Positions of the cells of a range, name or table column, row by row
+pub fn parse_to_rows(range: &str) -> Option<Vec<Vec<(u32, u32)>>> {
+    Some(reference_region(&resolve_name(range))?.rows())
+}
+
+// Scalar Vector Matrix to <CellArgument>
+// A range within a single column or row is a vector, any other range a matrix made of rows
+pub fn get_cell_argument(cell_id: &str) -> Option<CellArgument> {
+    let addresses = split_reference(&resolve_name(cell_id))?;
+    if let [address] = addresses.as_slice() {
+        let index = address.position()?;
+        return Some(CellArgument::Value(database_get_value(&index).cell_value));
+    }
+
+    let values: Vec<Vec<CellValue>> = Region::from_corners(&addresses[0], &addresses[1])
+        .rows()
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|index| database_get_value(index).cell_value)
+                .collect()
+        })
+        .collect();
+
+    if values.len() <= 1 || values.iter().all(|row| row.len() == 1) {
+        Some(CellArgument::Vector(values.concat()))
+    } else {
+        Some(CellArgument::Matrix(values))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The database is shared by every test, so each test stores its cells in rows of its own
+    fn store(cells: &[((u32, u32), i64)]) {
+        for (position, value) in cells {
+            database_insert(*position, CellRef::new(CellValue::Int(*value), None));
+        }
+    }
+
+    fn ints(values: &[i64]) -> Vec<CellValue> {
+        values.iter().map(|value| CellValue::Int(*value)).collect()
+    }
+
+    #[test]
+    fn rows_of_reversed_range() {
+        let expected = vec![
+            vec![(0, 1), (1, 1), (2, 1)],
+            vec![(0, 2), (1, 2), (2, 2)],
+            vec![(0, 3), (1, 3), (2, 3)],
+            vec![(0, 4), (1, 4), (2, 4)],
+            vec![(0, 5), (1, 5), (2, 5)],
+        ];
+        assert_eq!(reference_region("A1_C5").unwrap().rows(), expected);
+        assert_eq!(reference_region("C5_A1").unwrap().rows(), expected);
+        assert_eq!(reference_region("C1_A5").unwrap().rows(), expected);
+    }
+
+    #[test]
+    fn cells_pushed_off_the_sheet() {
+        let insert = Shift::Insert(Axis::Row, 10);
+        assert_eq!(insert.apply(&(0, 9)), Some((0, 9)));
+        assert_eq!(insert.apply(&(0, 10)), Some((0, 11)));
+        assert_eq!(insert.apply(&(0, u32::MAX)), None);
+        let region = reference_region("A5_A4294967295").unwrap();
+        assert_eq!(
+            insert.apply_region(&region).map(|region| region.bounds()),
+            Some(((0, 5), (0, u32::MAX)))
+        );
+        assert_eq!(Shift::Insert(Axis::Col, 0).apply(&(u32::MAX, 1)), None);
+    }
+
+    #[test]
+    fn cell_count_without_listing() {
+        assert_eq!(reference_region("A1_C5").unwrap().cell_count(), 15);
+        assert_eq!(
+            reference_region("A1_ZZZZZZ4294967295")
+                .unwrap()
+                .cell_count(),
+            321272406 * 4294967295
+        );
+    }
+
+    #[test]
+    fn parse_to_indices_row_by_row() {
+        assert_eq!(parse_to_indices("B2"), Some(vec![(1, 2)]));
+        assert_eq!(
+            parse_to_indices("A1_A3"),
+            Some(vec![(0, 1), (0, 2), (0, 3)])
+        );
+        assert_eq!(
+            parse_to_indices("C1_A1"),
+            Some(vec![(0, 1), (1, 1), (2, 1)])
+        );
+        assert_eq!(
+            parse_to_indices("B3_A2"),
+            Some(vec![(0, 2), (1, 2), (0, 3), (1, 3)])
+        );
+        assert_eq!(parse_to_indices("1A"), None);
+        assert_eq!(parse_to_indices(REFERENCE_ERROR), None);
+    }
+
+    #[test]
+    fn cell_argument_of_cell() {
+        store(&[((0, 101), 7)]);
+        assert_eq!(
+            get_cell_argument("A101"),
+            Some(CellArgument::Value(CellValue::Int(7)))
+        );
+        assert_eq!(
+            get_cell_argument("$A$101"),
+            Some(CellArgument::Value(CellValue::Int(7)))
+        );
+        assert_eq!(
+            get_cell_argument("B101"),
+            Some(CellArgument::Value(CellValue::None))
+        );
+    }
+
+    #[test]
+    fn cell_argument_of_row_vector() {
+        store(&[((0, 201), 1), ((1, 201), 2), ((2, 201), 3)]);
+        let expected = Some(CellArgument::Vector(ints(&[1, 2, 3])));
+        assert_eq!(get_cell_argument("A201_C201"), expected);
+        assert_eq!(get_cell_argument("C201_A201"), expected);
+    }
+
+    #[test]
+    fn cell_argument_of_column_vector() {
+        store(&[((0, 301), 1), ((0, 302), 2), ((0, 303), 3)]);
+        let expected = Some(CellArgument::Vector(ints(&[1, 2, 3])));
+        assert_eq!(get_cell_argument("A301_A303"), expected);
+        assert_eq!(get_cell_argument("A303_A301"), expected);
+    }
+
+    #[test]
+    fn cell_argument_of_matrix() {
+        store(&[
+            ((0, 401), 1),
+            ((1, 401), 2),
+            ((2, 401), 3),
+            ((0, 402), 4),
+            ((1, 402), 5),
+            ((2, 402), 6),
+        ]);
+        let expected = Some(CellArgument::Matrix(vec![
+            ints(&[1, 2, 3]),
+            ints(&[4, 5, 6]),
+        ]));
+        assert_eq!(get_cell_argument("A401_C402"), expected);
+        assert_eq!(get_cell_argument("C402_A401"), expected);
+        assert_eq!(get_cell_argument("A402_C401"), expected);
+    }
+
+    #[test]
+    fn cell_argument_of_non_reference() {
+        assert_eq!(get_cell_argument("1A"), None);
+        assert_eq!(get_cell_argument(REFERENCE_ERROR), None);
+    }
+}
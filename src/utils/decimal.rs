@@ -0,0 +1,280 @@
+use std::cmp::Ordering;
+use std::fmt::{self, Display, Formatter};
+
+// Most digits kept after the decimal point, longer results are rounded
+pub const MAX_SCALE: u32 = 18;
+
+// Extra digits a quotient keeps beyond those of its operands
+const DIVISION_SCALE: u32 = 6;
+
+// Fixed-point number, `units` counted in steps of 10^-scale, so `19.99` is 1999 at scale 2
+// Adding and subtracting is exact, which keeps sums of currency amounts free of rounding errors
+#[derive(Clone, Copy, Debug)]
+pub struct Decimal {
+    units: i128,
+    scale: u32,
+}
+
+fn power_of_ten(exponent: u32) -> Option<i128> {
+    10i128.checked_pow(exponent)
+}
+
+// Divide rounding half away from zero
+fn divide_rounded(numerator: i128, denominator: i128) -> Option<i128> {
+    let quotient = numerator.checked_div(denominator)?;
+    let remainder = numerator % denominator;
+    if remainder.unsigned_abs() * 2 >= denominator.unsigned_abs() {
+        let away = if (numerator < 0) == (denominator < 0) {
+            1
+        } else {
+            -1
+        };
+        quotient.checked_add(away)
+    } else {
+        Some(quotient)
+    }
+}
+
+impl Decimal {
+    pub fn from_int(number: i64) -> Self {
+        Decimal {
+            units: number as i128,
+            scale: 0,
+        }
+    }
+
+    // Parse `[-]digits.digits`, keeping every digit written after the point
+    pub fn parse(text: &str) -> Option<Self> {
+        let (sign, text) = match text.strip_prefix('-') {
+            Some(text) => (-1, text),
+            None => (1, text),
+        };
+        let (whole, fraction) = text.split_once('.')?;
+        let is_digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+        if !is_digits(whole) || !is_digits(fraction) || fraction.len() as u32 > MAX_SCALE {
+            return None;
+        }
+        let units: i128 = format!("{}{}", whole, fraction).parse().ok()?;
+        Some(Decimal {
+            units: sign * units,
+            scale: fraction.len() as u32,
+        })
+    }
+
+    // Nearest float, for arithmetic mixing decimals and floats
+    pub fn to_f64(self) -> f64 {
+        self.units as f64 / 10f64.powi(self.scale as i32)
+    }
+
+    // Nearest whole number, `None` if it does not fit
+    pub fn to_int(self) -> Option<i64> {
+        i64::try_from(self.rescale(0)?.units).ok()
+    }
+
+    // Nearest decimal of a float with `scale` digits after the point
+    pub fn from_f64(number: f64, scale: u32) -> Option<Self> {
+        if !number.is_finite() {
+            return None;
+        }
+        Decimal::parse(&format!("{:.*}", scale.max(1) as usize, number))?.rescale(scale)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.units == 0
+    }
+
+    // The same number with `scale` digits after the point, rounded half away from zero
+    pub fn rescale(&self, scale: u32) -> Option<Self> {
+        let units = if scale >= self.scale {
+            self.units.checked_mul(power_of_ten(scale - self.scale)?)?
+        } else {
+            divide_rounded(self.units, power_of_ten(self.scale - scale)?)?
+        };
+        Some(Decimal { units, scale })
+    }
+
+    // Both numbers at the larger of their scales
+    fn aligned(&self, other: &Decimal) -> Option<(i128, i128, u32)> {
+        let scale = self.scale.max(other.scale);
+        Some((
+            self.rescale(scale)?.units,
+            other.rescale(scale)?.units,
+            scale,
+        ))
+    }
+
+    pub fn checked_neg(&self) -> Option<Self> {
+        Some(Decimal {
+            units: self.units.checked_neg()?,
+            scale: self.scale,
+        })
+    }
+
+    pub fn checked_add(&self, other: &Decimal) -> Option<Self> {
+        let (a, b, scale) = self.aligned(other)?;
+        Some(Decimal {
+            units: a.checked_add(b)?,
+            scale,
+        })
+    }
+
+    pub fn checked_sub(&self, other: &Decimal) -> Option<Self> {
+        let (a, b, scale) = self.aligned(other)?;
+        Some(Decimal {
+            units: a.checked_sub(b)?,
+            scale,
+        })
+    }
+
+    pub fn checked_mul(&self, other: &Decimal) -> Option<Self> {
+        let product = Decimal {
+            units: self.units.checked_mul(other.units)?,
+            scale: self.scale + other.scale,
+        };
+        product.rescale(product.scale.min(MAX_SCALE))
+    }
+
+    // Quotient with a few more digits than the operands, rounded, `None` when dividing by zero
+    pub fn checked_div(&self, other: &Decimal) -> Option<Self> {
+        let scale = (self.scale.max(other.scale) + DIVISION_SCALE).min(MAX_SCALE);
+        let exponent = (scale + other.scale).checked_sub(self.scale)?;
+        let numerator = self.units.checked_mul(power_of_ten(exponent)?)?;
+        Some(Decimal {
+            units: divide_rounded(numerator, other.units)?,
+            scale,
+        })
+    }
+
+    pub fn checked_rem(&self, other: &Decimal) -> Option<Self> {
+        let (a, b, scale) = self.aligned(other)?;
+        Some(Decimal {
+            units: a.checked_rem(b)?,
+            scale,
+        })
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Decimal {}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// `1.5` and `1.50` are equal, numbers too large to align are compared as floats
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match self.aligned(other) {
+            Some((a, b, _)) => a.cmp(&b),
+            None => self.to_f64().total_cmp(&other.to_f64()),
+        }
+    }
+}
+
+impl Display for Decimal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let sign = if self.units < 0 { "-" } else { "" };
+        let digits = format!(
+            "{:0>width$}",
+            self.units.unsigned_abs(),
+            width = self.scale as usize + 1
+        );
+        let (whole, fraction) = digits.split_at(digits.len() - self.scale as usize);
+        if fraction.is_empty() {
+            write!(f, "{}{}", sign, whole)
+        } else {
+            write!(f, "{}{}.{}", sign, whole, fraction)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decimal(text: &str) -> Decimal {
+        Decimal::parse(text).unwrap()
+    }
+
+    fn text(number: Option<Decimal>) -> String {
+        number.unwrap().to_string()
+    }
+
+    #[test]
+    fn parse_and_display() {
+        assert_eq!(decimal("19.99").to_string(), "19.99");
+        assert_eq!(decimal("-0.50").to_string(), "-0.50");
+        assert_eq!(decimal("0.000001").to_string(), "0.000001");
+        assert_eq!(Decimal::from_int(-7).to_string(), "-7");
+        assert_eq!(Decimal::parse("1."), None);
+        assert_eq!(Decimal::parse(".5"), None);
+        assert_eq!(Decimal::parse("1e3"), None);
+        assert_eq!(Decimal::parse("1.-5"), None);
+        assert_eq!(Decimal::parse("0.1234567890123456789"), None);
+    }
+
+    #[test]
+    fn exact_addition_and_subtraction() {
+        assert_eq!(text(decimal("0.1").checked_add(&decimal("0.2"))), "0.3");
+        assert_eq!(
+            text(decimal("19.99").checked_sub(&decimal("20.00"))),
+            "-0.01"
+        );
+        assert_eq!(text(decimal("1.5").checked_add(&decimal("0.25"))), "1.75");
+        assert_eq!(
+            text(decimal("7.5").checked_rem(&Decimal::from_int(2))),
+            "1.5"
+        );
+    }
+
+    #[test]
+    fn multiplication_and_division() {
+        assert_eq!(text(decimal("1.5").checked_mul(&decimal("2.25"))), "3.375");
+        assert_eq!(
+            text(decimal("1.00").checked_div(&Decimal::from_int(3))),
+            "0.33333333"
+        );
+        assert_eq!(
+            text(decimal("2.0").checked_div(&decimal("3.0"))),
+            "0.6666667"
+        );
+        assert_eq!(decimal("1.0").checked_div(&Decimal::from_int(0)), None);
+
+        let product = text(decimal("1.123456789").checked_mul(&decimal("1.123456789123")));
+        assert_eq!(product.split_once('.').unwrap().1.len() as u32, MAX_SCALE);
+    }
+
+    #[test]
+    fn overflow() {
+        let large = decimal("10000000000000000000.000000000000000000");
+        assert_eq!(large.checked_mul(&large), None);
+        assert_eq!(
+            Decimal::parse("99999999999999999999999999999999999999.99"),
+            None
+        );
+    }
+
+    #[test]
+    fn rounding_half_away_from_zero() {
+        assert_eq!(text(decimal("2.345").rescale(2)), "2.35");
+        assert_eq!(text(decimal("-2.345").rescale(2)), "-2.35");
+        assert_eq!(text(decimal("2.344").rescale(2)), "2.34");
+        assert_eq!(decimal("2.5").to_int(), Some(3));
+        assert_eq!(decimal("-2.5").to_int(), Some(-3));
+        assert_eq!(text(Decimal::from_f64(0.1 + 0.2, 2)), "0.30");
+    }
+
+    #[test]
+    fn comparison_ignores_scale() {
+        assert_eq!(decimal("1.5"), decimal("1.50"));
+        assert!(decimal("1.49") < decimal("1.5"));
+        assert!(decimal("-0.01") < Decimal::from_int(0));
+    }
+}
@@ -1,7 +1,10 @@
+use crate::utils::database::Region;
+use crate::utils::region_index::RegionIndex;
 use lazy_static::lazy_static;
 use petgraph::algo::{tarjan_scc, toposort};
-use petgraph::graph::{DiGraph, NodeIndex, UnGraph};
-use petgraph::visit::{Dfs, EdgeRef, IntoNodeReferences, NodeRef};
+use petgraph::graph::{DiGraph, NodeIndex};
+use petgraph::visit::{Dfs, EdgeRef};
+use petgraph::Direction;
 use std::collections::{HashMap, HashSet};
 use std::sync::RwLock;
 
@@ -11,10 +14,31 @@ lazy_static! {
     static ref DEPENDENCIES: RwLock<DiGraph<(u32, u32), ()>> = RwLock::new(DiGraph::new());
 }
 
+// Ranges that each node depends on, kept as rectangles instead of one edge per cell
+// The cells affected by a change are found with a point query on the index
+struct RangeDependencies {
+    regions: HashMap<(u32, u32), Vec<Region>>,
+    index: RegionIndex<(u32, u32)>,
+}
+
+lazy_static! {
+    static ref RANGE_DEPENDENCIES: RwLock<RangeDependencies> = RwLock::new(RangeDependencies {
+        regions: HashMap::new(),
+        index: RegionIndex::new(),
+    });
+}
+
+// Nodes whose formulas use each defined name, so they can be re-linked when the name changes
+lazy_static! {
+    static ref NAME_DEPENDENCIES: RwLock<HashMap<String, HashSet<(u32, u32)>>> =
+        RwLock::new(HashMap::new());
+}
+
 // Enum of errors in topological ordering
 pub enum TopoError {
-    NodeNotFound,
-    CycleDetected(Vec<(u32, u32)>),
+    // All nodes in a cycle and the nodes that depend on them,
+    // followed by the topological ordering of the remaining nodes
+    CycleDetected(Vec<(u32, u32)>, Vec<(u32, u32)>),
 }
 
 // Update the dependency of node B
@@ -24,7 +48,7 @@ pub fn update_incoming_edges(a_vec: Vec<(u32, u32)>, b: (u32, u32)) {
     let node_b = find_or_add_node(&mut graph, b);
 
     let incoming_edges_to_remove: Vec<_> = graph
-        .edges_directed(node_b, petgraph::Direction::Incoming)
+        .edges_directed(node_b, Direction::Incoming)
         .map(|edge| edge.id())
         .collect();
     for edge_id in incoming_edges_to_remove {
@@ -37,222 +61,182 @@ pub fn update_incoming_edges(a_vec: Vec<(u32, u32)>, b: (u32, u32)) {
     }
 }
 
-fn find_or_add_node(graph: &mut DiGraph<(u32, u32), ()>, node: (u32, u32)) -> NodeIndex {
-    if let Some(index) = graph.node_indices().find(|&i| graph[i] == node) {
-        index
-    } else {
-        graph.add_node(node)
+// Update the ranges that node B depends on
+pub fn update_range_dependencies(regions: Vec<Region>, b: (u32, u32)) {
+    let mut ranges = RANGE_DEPENDENCIES.write().unwrap();
+
+    for region in ranges.regions.remove(&b).unwrap_or_default() {
+        ranges.index.remove(&region, &b);
+    }
+    for region in regions.iter() {
+        ranges.index.insert(region, b);
+    }
+    if !regions.is_empty() {
+        ranges.regions.insert(b, regions);
     }
 }
 
-// Calculating dependency chains, or self-dependency
-// If the node is not in the directed graph returns `TopoError<NodeNotFound>`
-// If the node is in cycle,
-//     returns all nodes `TopoError<CycleDetected(Vec<(u32,u32)>)>` in the strongly connected component in which the node is located
-//     and nodes that depend on nodes in the strongly connected component.
-// If the weakly connected component where the node is located is a directed acyclic graph,
-//     return the topological ordering `Vec<(u32,u32)`
-pub fn find_topology_sort_of_weakly_component(
-    node: (u32, u32),
-) -> Result<Vec<(u32, u32)>, TopoError> {
+// Update the defined names that node B uses
+pub fn update_name_dependencies(names: Vec<String>, b: (u32, u32)) {
+    let mut name_dependencies = NAME_DEPENDENCIES.write().unwrap();
+
+    for dependents in name_dependencies.values_mut() {
+        dependents.remove(&b);
+    }
+    name_dependencies.retain(|_, dependents| !dependents.is_empty());
+    for name in names {
+        name_dependencies.entry(name).or_default().insert(b);
+    }
+}
+
+// Nodes whose formulas use the defined name
+pub fn find_name_dependents(name: &str) -> Vec<(u32, u32)> {
+    let name_dependencies = NAME_DEPENDENCIES.read().unwrap();
+    name_dependencies
+        .get(name)
+        .map(|dependents| dependents.iter().copied().collect())
+        .unwrap_or_default()
+}
+
+// Nodes that directly depend on node A, in order of position
+pub fn find_direct_dependents(a: (u32, u32)) -> Vec<(u32, u32)> {
     let graph = DEPENDENCIES.read().unwrap();
+    let ranges = RANGE_DEPENDENCIES.read().unwrap();
+    let mut dependents = find_dependents(&graph, &ranges, a);
+    dependents.sort_by_key(|&(col, row)| (row, col));
+    dependents.dedup();
+    dependents
+}
 
-    // Convert directed graph to undirected graph
-    // The weakly connected components of a directed graph are the same as
-    // the connected components of the corresponding undirected graph
-    let mut undirected_graph = UnGraph::<(u32, u32), ()>::new_undirected();
-    let mut node_to_index = HashMap::new();
+// Move every node to its new position after a structural change to the sheet
+// Nodes for which `renumber` returns `None` are removed together with their edges and ranges
+// The ranges themselves are left for the caller to update
+pub fn renumber_nodes(renumber: impl Fn(&(u32, u32)) -> Option<(u32, u32)>) {
+    let mut graph = DEPENDENCIES.write().unwrap();
 
-    for node_ref in graph.node_references() {
-        let (a, b) = *node_ref.weight();
-        node_to_index.insert((a, b), undirected_graph.add_node((a, b)));
+    graph.retain_nodes(|graph, index| renumber(&graph[index]).is_some());
+    for weight in graph.node_weights_mut() {
+        if let Some(position) = renumber(weight) {
+            *weight = position;
+        }
     }
 
-    for edge_ref in graph.edge_references() {
-        let source = undirected_graph.node_weight(node_to_index[&graph[edge_ref.source()]]);
-        let target = undirected_graph.node_weight(node_to_index[&graph[edge_ref.target()]]);
-        if let (Some(source), Some(target)) = (source, target) {
-            undirected_graph.update_edge(node_to_index[source], node_to_index[target], ());
+    let mut ranges = RANGE_DEPENDENCIES.write().unwrap();
+    let regions: HashMap<_, _> = ranges
+        .regions
+        .drain()
+        .filter_map(|(b, regions)| renumber(&b).map(|b| (b, regions)))
+        .collect();
+    ranges.index = RegionIndex::new();
+    for (b, regions) in regions.iter() {
+        for region in regions {
+            ranges.index.insert(region, *b);
         }
     }
+    ranges.regions = regions;
 
-    // If the node is not in the directed graph returns `TopoError<NodeNotFound>`
-    let node_index = node_to_index.get(&node);
-    if node_index.is_none() {
-        return Err(TopoError::NodeNotFound);
+    let mut name_dependencies = NAME_DEPENDENCIES.write().unwrap();
+    for dependents in name_dependencies.values_mut() {
+        *dependents = dependents.iter().filter_map(&renumber).collect();
     }
-    let node_index = *node_index.unwrap();
+}
 
-    // Using DFS algorithm to calculate the connected components of an undirected graph
-    let mut connected_component = HashSet::from([node_index]);
-    let mut dfs = Dfs::new(&undirected_graph, node_index);
-    while let Some(node_idx) = dfs.next(&undirected_graph) {
-        connected_component.insert(node_idx);
+fn find_or_add_node(graph: &mut DiGraph<(u32, u32), ()>, node: (u32, u32)) -> NodeIndex {
+    if let Some(index) = graph.node_indices().find(|&i| graph[i] == node) {
+        index
+    } else {
+        graph.add_node(node)
     }
+}
 
-    // Find the maximal connected subgraph
-    let subgraph_nodes: Vec<NodeIndex> = connected_component.iter().copied().collect();
-    let subgraph = graph.filter_map(
-        |index, weight| {
-            if subgraph_nodes.contains(&index) {
-                Some(*weight)
-            } else {
-                None
-            }
-        },
-        |_, _| Some(()),
-    );
+// Nodes that directly depend on node A, through an edge or through a range containing A
+fn find_dependents(
+    graph: &DiGraph<(u32, u32), ()>,
+    ranges: &RangeDependencies,
+    a: (u32, u32),
+) -> Vec<(u32, u32)> {
+    let mut dependents = ranges.index.query(&a);
+    if let Some(node_a) = graph.node_indices().find(|&i| graph[i] == a) {
+        dependents.extend(
+            graph
+                .neighbors_directed(node_a, Direction::Outgoing)
+                .map(|index| graph[index]),
+        );
+    }
+    dependents
+}
 
-    // Perform topological sort
-    match toposort(&subgraph, None) {
-        //If the weakly connected component where the node is located is a directed acyclic graph,
-        //     return the topological ordering `Vec<(u32,u32)`
-        Ok(sorted_indices) => {
-            let sorted_values = sorted_indices
-                .iter()
-                .map(|&index| *subgraph.node_weight(index).unwrap())
-                .collect::<Vec<_>>();
-            Ok(sorted_values)
-        }
-        // If the node is in cycle,
-        //     returns all nodes `TopoError<CycleDetected(Vec<(u32,u32)>)>` in the strongly connected component in which the node is located
-        //     and nodes that depend on nodes in the strongly connected component.
-        Err(cycle) => {
-            let cycle_node_id = cycle.node_id();
-            // Compute strongly connected component on the graph
-            let scc = tarjan_scc(&*graph);
+// Calculating dependency chains, or self-dependency, of the nodes that changed
+// Only the changed nodes and the nodes depending on them, directly or indirectly, are sorted
+// If there is no cycle among them, return the topological ordering `Vec<(u32,u32)`
+// If there is a cycle,
+//     returns all nodes `TopoError<CycleDetected>` in strongly connected components
+//     and nodes that depend on nodes in the strongly connected components,
+//     along with the topological ordering of the other nodes
+pub fn find_topology_sort_of_dependents(
+    nodes: &[(u32, u32)],
+) -> Result<Vec<(u32, u32)>, TopoError> {
+    let graph = DEPENDENCIES.read().unwrap();
+    let ranges = RANGE_DEPENDENCIES.read().unwrap();
 
-            // Find the strongly connected component that contains the cycle node
-            let cycle_component = scc
-                .iter()
-                .find(|comp| comp.contains(&cycle_node_id))
-                .unwrap();
+    // Collect the changed nodes and everything that depends on them into a subgraph
+    let mut subgraph = DiGraph::<(u32, u32), ()>::new();
+    let mut node_to_index = HashMap::new();
+    let mut stack = Vec::new();
+    for node in nodes {
+        node_to_index.entry(*node).or_insert_with(|| {
+            stack.push(*node);
+            subgraph.add_node(*node)
+        });
+    }
+    while let Some(a) = stack.pop() {
+        let index_a = node_to_index[&a];
+        for b in find_dependents(&graph, &ranges, a) {
+            let index_b = *node_to_index.entry(b).or_insert_with(|| {
+                stack.push(b);
+                subgraph.add_node(b)
+            });
+            subgraph.update_edge(index_a, index_b, ());
+        }
+    }
 
+    // Perform topological sort
+    match toposort(&subgraph, None) {
+        Ok(sorted_indices) => Ok(sorted_indices
+            .iter()
+            .map(|&index| subgraph[index])
+            .collect()),
+        Err(_) => {
+            // A strongly connected component is a cycle if it has several nodes or a self loop
             // Find the nodes that depend on the strongly connected components
-            let mut visited_nodes = HashSet::new();
-            for node_idx in cycle_component {
-                let mut dfs = Dfs::new(&*graph, *node_idx);
-                while let Some(nx) = dfs.next(&*graph) {
-                    visited_nodes.insert(*graph.node_weight(nx).unwrap());
+            let mut cyclic_nodes = HashSet::new();
+            for component in tarjan_scc(&subgraph) {
+                if component.len() == 1 && !subgraph.contains_edge(component[0], component[0]) {
+                    continue;
+                }
+                for node_idx in component {
+                    let mut dfs = Dfs::new(&subgraph, node_idx);
+                    while let Some(nx) = dfs.next(&subgraph) {
+                        cyclic_nodes.insert(nx);
+                    }
                 }
             }
 
+            // The remaining nodes can still be sorted
+            let acyclic_subgraph = subgraph.filter_map(
+                |index, weight| (!cyclic_nodes.contains(&index)).then_some(*weight),
+                |_, _| Some(()),
+            );
+            let sorted_values = toposort(&acyclic_subgraph, None)
+                .unwrap_or_default()
+                .iter()
+                .map(|&index| acyclic_subgraph[index])
+                .collect();
+
             Err(TopoError::CycleDetected(
-                visited_nodes.into_iter().collect(),
+                cyclic_nodes.iter().map(|&index| subgraph[index]).collect(),
+                sorted_values,
             ))
         }
     }
 }
-
-// Test functions for algorithms such as
-// construction of dependency graphs,
-// compute the topological ordering of weakly connected components,
-// and finding rings (strongly connected components)
-
-// #[allow(dead_code)]
-// pub fn __test() {
-//
-//     // test1:
-//     // Graph:
-//     // 1 -> 4 -> 5  6 -> 7
-//     // ↓ ↗ ↓ ↗      ↗
-//     // 2 -> 3       8
-//     // Topological sort:
-//     // 1->2->4->3->5
-//
-//     update_incoming_edges([(1, 1), (2, 2)].to_vec(), (4, 4));
-//     update_incoming_edges([(1, 1)].to_vec(), (2, 2));
-//     update_incoming_edges([(3, 3), (4, 4)].to_vec(), (5, 5));
-//     update_incoming_edges([(2, 2), (4, 4)].to_vec(), (3, 3));
-//     update_incoming_edges([(6, 6), (8, 8)].to_vec(), (7, 7));
-//     let graph = DEPENDENCIES.read().unwrap();
-//     println!("{:?}", graph);
-//     match find_topology_sort_of_weakly_component((2, 2)) {
-//         Ok(topo_sort) => println!("Topological sort: {:?}", topo_sort),
-//         Err(TopoError::NodeNotFound) => println!("Error: Node not found"),
-//         Err(TopoError::CycleDetected(cycle)) => println!("Detected cycle: {:?}", cycle),
-//     };
-//
-//     drop(graph);
-//
-//     // test2:
-//     // Graph:
-//     // 1 -> 4 -> 5 -> 9  6 -> 7
-//     // ↓ ↗ ↓ ↗           ↗
-//     // 2 -> 3            8
-//     // Topological sort:
-//     // 1->2->4->3->5->9
-//
-//     update_incoming_edges([(5, 5)].to_vec(), (9, 9));
-//     let graph = DEPENDENCIES.read().unwrap();
-//     println!("{:?}", graph);
-//     match find_topology_sort_of_weakly_component((2, 2)) {
-//         Ok(topo_sort) =>                                                                                                                                                                                                                                                                                                    ,
-//         Err(TopoError::NodeNotFound) => println!("Error: Node not found"),
-//         Err(TopoError::CycleDetected(cycle)) => println!("Detected cycle: {:?}", cycle),
-//     }
-//     drop(graph);
-//
-//     //test3:
-//     // Graph:
-//     // 1 -> 4 -> 5 -> 9  6 -> 7
-//     // ↓ ↗   ↗           ↗
-//     // 2    3            8
-//     // Topological sort:
-//     // 3->1->2->4->5->9
-//
-//     update_incoming_edges([].to_vec(), (3, 3));
-//     let graph = DEPENDENCIES.read().unwrap();
-//     println!("{:?}", graph);
-//     match find_topology_sort_of_weakly_component((2, 2)) {
-//         Ok(topo_sort) => println!("Topological sort: {:?}", topo_sort),
-//         Err(TopoError::NodeNotFound) => println!("Error: Node not found"),
-//         Err(TopoError::CycleDetected(cycle)) => println!("Detected cycle: {:?}", cycle),
-//     }
-//     drop(graph);
-//
-//     // test4:
-//     // Graph:
-//     // 3 -> 5 -> 9
-//     //   ↙ ↑
-//     // 1    4    6 -> 7
-//     // ↓ ↗        ↗
-//     // 2         8
-//     // Detected cycle
-//     // Return the nodes in the strongly connected component
-//     // and the nodes that depend on the nodes in the strongly connected component
-//     // [2,1,5,4,9]
-//     // Strongly connected component:
-//     // [2,1,5,4]
-//     // Nodes depend on strongly connected components
-//     // [9]
-//
-//     update_incoming_edges([(5, 5)].to_vec(), (1, 1));
-//     let graph = DEPENDENCIES.read().unwrap();
-//     println!("{:?}", graph);
-//     match find_topology_sort_of_weakly_component((2, 2)) {
-//         Ok(topo_sort) => println!("Topological sort: {:?}", topo_sort),
-//         Err(TopoError::NodeNotFound) => println!("Error: Node not found"),
-//         Err(TopoError::CycleDetected(cycle)) => println!("Detected cycle: {:?}", cycle),
-//     }
-//     drop(graph);
-//
-//     // test5:
-//     // Graph:
-//     // 3 -> 5 -> 9
-//     //   ↙
-//     // 1 -> 4    6 -> 7
-//     // ↓ ↗        ↗
-//     // 2         8
-//     // Topological sort:
-//     // 3->5->9->1->2->4
-//
-//     update_incoming_edges([(3, 3)].to_vec(), (5, 5));
-//     let graph = DEPENDENCIES.read().unwrap();
-//     println!("{:?}", graph);
-//     match find_topology_sort_of_weakly_component((2, 2)) {
-//         Ok(topo_sort) => println!("Topological sort: {:?}", topo_sort),
-//         Err(TopoError::NodeNotFound) => println!("Error: Node not found"),
-//         Err(TopoError::CycleDetected(cycle)) => println!("Detected cycle: {:?}", cycle),
-//     }
-// }
@@ -1,120 +1,645 @@
+use crate::utils::audit::{audit_record, audit_shift, AuditEntry};
+use crate::utils::auth::Session;
+use crate::utils::cell_format::CellFormat;
+use crate::utils::command::Response;
 use crate::utils::database::{
-    database_get_value, database_insert, get_cell_argument, parse_to_indices, pos_to_cell_id,
-    split_cell_id, CellRef,
+    database_formulas, database_get_value, database_insert, database_protected,
+    database_set_format, database_set_protected, database_set_value, database_shift, is_valid_name,
+    join_reference, move_reference, names_insert, names_list, names_remove, offset_reference,
+    pos_to_cell_id, resolve_name, split_cell_id, split_reference, split_table_column, table_bounds,
+    tables_insert, tables_list, CellAddress, CellRef, Region, Shift,
 };
 use crate::utils::dependency_manager::TopoError::CycleDetected;
 use crate::utils::dependency_manager::{
-    find_topology_sort_of_weakly_component, update_incoming_edges,
+    find_name_dependents, find_topology_sort_of_dependents, renumber_nodes, update_incoming_edges,
+    update_name_dependencies, update_range_dependencies,
 };
-use rsheet_lib::cell_value::CellValue;
-use rsheet_lib::command_runner::CommandRunner;
-use rsheet_lib::replies::Reply;
-use std::collections::HashMap;
+use crate::utils::evaluator::evaluate_formula;
+use crate::utils::formula::rewrite_words;
+use crate::utils::functions::{register_user_functions, UserFunction};
+use crate::utils::metrics::{record_queued, record_recalculation};
+use crate::utils::parser::{parse, Expr};
+use crate::utils::time::DateTime;
+use crate::utils::value::CellValue;
+use lazy_static::lazy_static;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::sync::mpsc;
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Source and destination cell of a paste
+pub type PastedCell = ((u32, u32), (u32, u32));
+
+// Requests that modify the spreadsheet
+pub enum Request {
+    // Set the cell with the given id to an expression
+    Set(String, String),
+    // Insert or delete a row or column
+    Shift(Shift),
+    // Copy the contents of each source cell to its destination cell,
+    // moving relative references by the distance between them
+    // With `cut` set, source cells that are not overwritten are cleared afterwards
+    Paste { cells: Vec<PastedCell>, cut: bool },
+    // Define or redefine a name for a cell or range
+    DefineName(String, String),
+    // Remove a defined name
+    DropName(String),
+    // Create a table over a range whose top row holds the column headers
+    CreateTable(String, String),
+    // Attach a display format to cells, or remove it with `None`
+    Format(Vec<(u32, u32)>, Option<CellFormat>),
+    // Protect cells from changes, or lift their protection with `false`
+    Protect(Vec<(u32, u32)>, bool),
+    // Change nothing, replying once every request queued before it was applied
+    Sync,
+}
+
+impl Request {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Set(..) => "set",
+            Request::Shift(_) => "shift",
+            Request::Paste { .. } => "paste",
+            Request::DefineName(..) => "define_name",
+            Request::DropName(_) => "drop_name",
+            Request::CreateTable(..) => "create_table",
+            Request::Format(..) => "format",
+            Request::Protect(..) => "protect",
+            Request::Sync => "sync",
+        }
+    }
+}
+
+// Cells recomputed by a request, with their new values
+pub type Changes = Vec<((u32, u32), CellValue)>;
+
+// Listeners told about the cells every request recomputed
+lazy_static! {
+    static ref CHANGE_SUBSCRIBERS: Mutex<Vec<Sender<Changes>>> = Mutex::new(Vec::new());
+}
+
+thread_local! {
+    // Cells recomputed by the request being executed
+    static CHANGED_CELLS: RefCell<Vec<(u32, u32)>> = const { RefCell::new(Vec::new()) };
+}
+
+// Receive the cells recomputed by each request from now on, once the request has been applied
+// A subscription ends when its receiver is dropped
+pub fn subscribe_changes() -> Receiver<Changes> {
+    let (tx, rx) = mpsc::channel();
+    CHANGE_SUBSCRIBERS.lock().unwrap().push(tx);
+    rx
+}
+
+// Send the cells recomputed by the last request to every subscriber
+// Returns the number of cells recomputed
+fn notify_changes() -> usize {
+    let mut cells = CHANGED_CELLS.with(|changed| std::mem::take(&mut *changed.borrow_mut()));
+    if cells.is_empty() {
+        return 0;
+    }
+    cells.sort_by_key(|&(col, row)| (row, col));
+    cells.dedup();
+    let changes: Changes = cells
+        .into_iter()
+        .map(|cell| (cell, database_get_value(&cell).cell_value))
+        .collect();
+
+    let mut subscribers = CHANGE_SUBSCRIBERS.lock().unwrap();
+    subscribers.retain(|subscriber| subscriber.send(changes.clone()).is_ok());
+    changes.len()
+}
 
 pub struct Transaction {
-    request: Vec<String>,
-    responder: Sender<Option<Reply>>,
+    request: Request,
+    // Connection the request came from
+    session: Session,
+    responder: Sender<Option<Response>>,
 }
 
 impl Transaction {
-    pub fn new(request: Vec<String>, responder: Sender<Option<Reply>>) -> Self {
-        Transaction { request, responder }
+    pub fn new(request: Request, session: Session, responder: Sender<Option<Response>>) -> Self {
+        record_queued(true);
+        Transaction {
+            request,
+            session,
+            responder,
+        }
     }
 }
 
-pub fn execute_transactions(rx: mpsc::Receiver<Transaction>) {
+pub fn execute_transactions(rx: mpsc::Receiver<Transaction>, functions: Vec<UserFunction>) {
+    register_user_functions(functions);
+
     for transaction in rx {
-        let request = transaction.request;
-
-        // Handling the set command
-        if let Some(cell_position) = split_cell_id(&request[0]) {
-            let runner = CommandRunner::new(&request[1]);
-            let variables = runner.find_variables();
-            let mut var_list = Vec::new();
-
-            // Get the keys of all the cells that the cell depends on
-            for var in variables.iter() {
-                match &mut parse_to_indices(var) {
-                    Some(result) => var_list.append(result),
-                    None => {
-                        transaction
-                            .responder
-                            .send(Some(Reply::Error(format!(
-                                "Error: Invalid Key Provided: {}",
-                                var
-                            ))))
-                            .unwrap();
-                        break;
-                    }
-                }
+        record_queued(false);
+        let started = Instant::now();
+        let reply = match &transaction.request {
+            Request::Set(cell_id, expr) => set_cell(cell_id, expr, &transaction.session),
+            Request::Shift(shift) => shift_cells(shift, &transaction.session),
+            Request::Paste { cells, cut } => paste_cells(cells, *cut, &transaction.session),
+            Request::DefineName(name, reference) => {
+                names_insert(name.clone(), reference.clone());
+                relink_name(name);
+                None
             }
-            // Add the set cells to the hashmap.
-            if var_list.is_empty() {
-                let cell_value = runner.run(&HashMap::new());
-                database_insert(cell_position, CellRef::new(cell_value, None));
-            } else {
-                database_insert(
-                    cell_position,
-                    CellRef::new(CellValue::None, Some(String::from(&request[1]))),
-                );
+            Request::DropName(name) => {
+                names_remove(name);
+                relink_name(name);
+                None
             }
-
-            // Updating the dependency graph
-            // Add edges of dependent cells pointing to set cells
-            update_incoming_edges(var_list, cell_position);
-
-            // Perform topological sorting
-            match find_topology_sort_of_weakly_component(cell_position) {
-                // Updating cell values in topological order
-                Ok(topological_order) => {
-                    for cell in topological_order.iter() {
-                        let cell_value = database_get_value(cell);
-                        if let Some(expr) = cell_value.dependency {
-                            let runner = CommandRunner::new(&expr);
-                            let var_list = runner.find_variables();
-                            let mut variables = HashMap::new();
-                            for id in var_list.iter() {
-                                if let Some(cell_arg) = get_cell_argument(id) {
-                                    variables.insert(id.clone(), cell_arg);
-                                }
-                            }
-                            database_insert(
-                                *cell,
-                                CellRef::new(runner.run(&variables), Some(expr)),
-                            );
-                        }
-                    }
+            Request::CreateTable(table, reference) => {
+                tables_insert(table.clone(), reference.clone());
+                relink_name(table);
+                None
+            }
+            Request::Format(cells, format) => {
+                for cell in cells {
+                    database_set_format(*cell, format.clone());
                 }
-                Err(topo_error) => {
-                    // If a self-referencing error is detected, set an error message for all error cells
-                    if let CycleDetected(cell_self_ref) = topo_error {
-                        for cell in cell_self_ref.iter() {
-                            let cell_value = database_get_value(cell);
-                            database_insert(
-                                *cell,
-                                CellRef::new(
-                                    CellValue::Error(format!(
-                                        "Error: Cell {} is self-referential",
-                                        pos_to_cell_id(cell)
-                                    )),
-                                    cell_value.dependency,
-                                ),
-                            );
-                        }
-                    }
+                None
+            }
+            Request::Protect(cells, protected) => {
+                for cell in cells {
+                    database_set_protected(*cell, *protected);
                 }
-            };
-        } else {
-            transaction
-                .responder
-                .send(Some(Reply::Error(format!(
-                    "Error: Invalid Key Provided: {}",
-                    request[0]
-                ))))
-                .unwrap()
-        }
-        transaction.responder.send(None).unwrap()
+                None
+            }
+            Request::Sync => None,
+        };
+        let cells = notify_changes();
+        log::debug!(
+            connection = transaction.session.connection,
+            request = transaction.request.name(),
+            cells,
+            ok = !matches!(reply, Some(Response::Error(_))),
+            duration_us = started.elapsed().as_micros() as u64;
+            "request applied"
+        );
+        transaction.responder.send(reply).unwrap()
+    }
+}
+
+// Handling the set command, recording it in the audit log
+fn set_cell(cell_id: &str, expr: &str, session: &Session) -> Option<Response> {
+    match split_cell_id(cell_id) {
+        Some(cell_position) => {
+            if let Some(e) = reject_protected([cell_position]) {
+                return Some(e);
+            }
+            let old = database_get_value(&cell_position);
+            store_expression(cell_position, expr);
+            let new_value = database_get_value(&cell_position).cell_value;
+            audit_change(
+                session,
+                cell_position,
+                old,
+                Some(expr.to_string()),
+                new_value,
+            );
+            None
+        }
+        None => Some(Response::Error(format!(
+            "Error: Invalid Key Provided: {}",
+            cell_id
+        ))),
+    }
+}
+
+// Formula a cell holds, or its value if it has none, for the audit log
+fn cell_expression(cell_ref: &CellRef) -> Option<String> {
+    match (&cell_ref.dependency, &cell_ref.cell_value) {
+        (Some(dependency), _) => Some(dependency.clone()),
+        (None, CellValue::None) => None,
+        (None, value) => Some(value.to_text()),
+    }
+}
+
+// Record a change of a cell made by a connection, nothing is recorded for an empty cell left empty
+fn audit_change(
+    session: &Session,
+    cell_position: (u32, u32),
+    old: CellRef,
+    new_expr: Option<String>,
+    new_value: CellValue,
+) {
+    let old_expr = cell_expression(&old);
+    if old_expr.is_none() && new_expr.is_none() {
+        return;
+    }
+    audit_record(AuditEntry {
+        time: DateTime::now(),
+        connection: session.connection,
+        user: session.user.clone(),
+        cell: cell_position,
+        old_expr,
+        new_expr,
+        old_value: old.cell_value,
+        new_value,
+    });
+}
+
+// Record the changes of cells after they were written, from what they held before
+fn audit_written(session: &Session, old: Vec<((u32, u32), CellRef)>) {
+    for (cell_position, old) in old {
+        let new = database_get_value(&cell_position);
+        audit_change(
+            session,
+            cell_position,
+            old,
+            cell_expression(&new),
+            new.cell_value,
+        );
+    }
+}
+
+// Store an expression in a cell and recalculate everything that depends on it
+// The expression is parsed once here, an expression that does not parse is kept as text
+// and the cell holds its parse error
+fn store_expression(cell_position: (u32, u32), expr: &str) {
+    let formula = parse(expr);
+
+    // Updating the dependency graph
+    // Add edges of dependent cells pointing to set cells
+    let has_dependencies = link_dependencies(cell_position, formula.as_ref().ok());
+
+    // Add the set cells to the hashmap.
+    let cell_ref = match formula {
+        Ok(formula) if has_dependencies => {
+            CellRef::with_formula(CellValue::None, String::from(expr), formula)
+        }
+        Ok(formula) => CellRef::new(evaluate_formula(&formula), None),
+        Err(e) => CellRef::new(
+            CellValue::Error(format!("Error: {}", e)),
+            Some(String::from(expr)),
+        ),
+    };
+    database_insert(cell_position, cell_ref);
+    update_tables(cell_position);
+
+    recalculate(&[cell_position]);
+}
+
+// Store a value that does not depend on other cells and recalculate everything that depends on it
+fn store_value(cell_position: (u32, u32), cell_value: CellValue) {
+    database_insert(cell_position, CellRef::new(cell_value, None));
+    link_dependencies(cell_position, None);
+    update_tables(cell_position);
+    recalculate(&[cell_position]);
+}
+
+// Register the cells, ranges and names that a formula depends on as the dependencies of a cell,
+// without a formula the cell depends on nothing
+// Returns whether the formula depends on anything
+fn link_dependencies(cell_position: (u32, u32), formula: Option<&Expr>) -> bool {
+    let (var_list, regions, names) = formula.map(find_dependencies).unwrap_or_default();
+    let has_dependencies = !(var_list.is_empty() && regions.is_empty() && names.is_empty());
+    update_incoming_edges(var_list, cell_position);
+    update_range_dependencies(regions, cell_position);
+    update_name_dependencies(names, cell_position);
+    has_dependencies
+}
+
+// Re-register the dependencies of every formula using a name after it was defined or dropped
+fn relink_name(name: &str) {
+    let cells = find_name_dependents(name);
+    for cell in cells.iter() {
+        if let Some(formula) = database_get_value(cell).formula {
+            link_dependencies(*cell, Some(&formula));
+        }
+    }
+    recalculate(&cells);
+}
+
+// Grow every table that a written cell was appended below, and re-link the formulas
+// using a table when one of its headers changed
+fn update_tables(cell_position: (u32, u32)) {
+    let cell_ref = database_get_value(&cell_position);
+    let is_blank = cell_ref.cell_value == CellValue::None && cell_ref.dependency.is_none();
+
+    for (table, _) in tables_list() {
+        let ((left, top), (right, bottom)) = match table_bounds(&table) {
+            Some(bounds) => bounds,
+            None => continue,
+        };
+        if !(left..=right).contains(&cell_position.0) {
+            continue;
+        }
+        if cell_position.1 == bottom + 1 && !is_blank {
+            let reference = join_reference(&[
+                CellAddress::new((left, top)),
+                CellAddress::new((right, bottom + 1)),
+            ]);
+            tables_insert(table.clone(), reference);
+            relink_name(&table);
+        } else if cell_position.1 == top {
+            relink_name(&table);
+        }
+    }
+}
+
+// Error for the first protected cell among cells a request would change
+fn reject_protected(cells: impl IntoIterator<Item = (u32, u32)>) -> Option<Response> {
+    cells
+        .into_iter()
+        .find(|cell| database_get_value(cell).protected)
+        .map(|cell| Response::Error(format!("Error: Protected cell: {}", pos_to_cell_id(&cell))))
+}
+
+// Handling the copy, move and fill commands
+// Nothing is pasted if a destination, or a source being cut, is protected
+// Every cell written is recorded in the audit log, destinations and cleared sources alike
+fn paste_cells(cells: &[PastedCell], cut: bool, session: &Session) -> Option<Response> {
+    let changed = cells.iter().map(|(_, to)| *to);
+    let cut_sources = cells.iter().filter(|_| cut).map(|(from, _)| *from);
+    let mut written: Vec<_> = changed.chain(cut_sources).collect();
+    written.sort();
+    written.dedup();
+    if let Some(e) = reject_protected(written.iter().copied()) {
+        return Some(e);
+    }
+    let old: Vec<_> = written
+        .into_iter()
+        .map(|cell| (cell, database_get_value(&cell)))
+        .collect();
+    let following = if cut {
+        formulas_following(cells)
+    } else {
+        Vec::new()
+    };
+
+    // Read every source first so that overlapping ranges copy the original contents
+    let contents: Vec<_> = cells
+        .iter()
+        .map(|(from, to)| (database_get_value(from), *from, *to))
+        .collect();
+
+    if cut {
+        let destinations: HashSet<_> = cells.iter().map(|(_, to)| *to).collect();
+        for (from, _) in cells {
+            if !destinations.contains(from) {
+                store_value(*from, CellValue::None);
+            }
+        }
+    }
+
+    for (cell_ref, from, to) in contents {
+        let offset = (to.0 as i64 - from.0 as i64, to.1 as i64 - from.1 as i64);
+        match cell_ref.dependency {
+            Some(expr) => {
+                let expr = rewrite_words(&expr, |word| offset_reference(word, offset));
+                store_expression(to, &expr);
+            }
+            None => store_value(to, cell_ref.cell_value),
+        }
+    }
+    audit_written(session, old);
+    if cut {
+        follow_moved_cells(cells, following, session);
+    }
+    None
+}
+
+// Formulas referring to cells about to be moved, with what they hold and how they are rewritten
+// Read before the move, so that the audit log records the values they had until then
+// The pasted formulas are rewritten for their new position instead, so they are left alone
+fn formulas_following(cells: &[PastedCell]) -> Vec<((u32, u32), CellRef, String)> {
+    let moved: HashMap<_, _> = cells.iter().copied().collect();
+    let destinations: HashSet<_> = moved.values().copied().collect();
+    database_formulas()
+        .into_iter()
+        .filter(|(cell_position, _)| {
+            !moved.contains_key(cell_position) && !destinations.contains(cell_position)
+        })
+        .filter_map(|(cell_position, expr)| {
+            let rewritten = rewrite_words(&expr, |word| move_reference(word, &moved));
+            (rewritten != expr)
+                .then(|| (cell_position, database_get_value(&cell_position), rewritten))
+        })
+        .collect()
+}
+
+// Point the formulas, names and tables that referred to moved cells at where the cells went
+fn follow_moved_cells(
+    cells: &[PastedCell],
+    formulas: Vec<((u32, u32), CellRef, String)>,
+    session: &Session,
+) {
+    let moved: HashMap<_, _> = cells.iter().copied().collect();
+
+    for (name, reference) in names_list() {
+        if let Some(reference) = move_reference(&reference, &moved) {
+            names_insert(name.clone(), reference);
+            relink_name(&name);
+        }
+    }
+    for (table, reference) in tables_list() {
+        if let Some(reference) = move_reference(&reference, &moved) {
+            tables_insert(table.clone(), reference);
+            relink_name(&table);
+        }
+    }
+
+    let mut changed = Vec::new();
+    for (cell_position, old, rewritten) in formulas {
+        let formula = parse(&rewritten);
+        link_dependencies(cell_position, formula.as_ref().ok());
+        let cell_value = database_get_value(&cell_position).cell_value;
+        let cell_ref = match formula {
+            Ok(formula) => CellRef::with_formula(cell_value, rewritten, formula),
+            Err(_) => CellRef::new(cell_value, Some(rewritten)),
+        };
+        database_insert(cell_position, cell_ref);
+        changed.push((cell_position, old));
+    }
+    let cells: Vec<_> = changed.iter().map(|(position, _)| *position).collect();
+    recalculate(&cells);
+    audit_written(session, changed);
+}
+
+// Insert or delete a row or column
+// Cells are moved, dependency graph nodes renumbered and formulas rewritten
+// so that every reference keeps pointing at the same logical data
+// Deleted cells and rewritten formulas are recorded in the audit log
+fn shift_cells(shift: &Shift, session: &Session) -> Option<Response> {
+    // Deleting a row or column would remove its protected cells
+    let deleted = database_protected()
+        .into_iter()
+        .filter(|cell| shift.apply(cell).is_none());
+    if let Some(e) = reject_protected(deleted) {
+        return Some(e);
+    }
+
+    let deleted = database_shift(shift);
+    renumber_nodes(|position| shift.apply(position));
+
+    // Names and tables follow the cells they refer to
+    for (name, reference) in names_list() {
+        if let Some(reference) = shift.rewrite_reference(&reference) {
+            names_insert(name, reference);
+        }
+    }
+    for (table, reference) in tables_list() {
+        if let Some(reference) = shift.rewrite_reference(&reference) {
+            tables_insert(table, reference);
+        }
+    }
+
+    let formulas = database_formulas();
+    let mut rewritten = Vec::new();
+    for (cell_position, old_expr) in formulas.iter() {
+        let expr = rewrite_words(old_expr, |word| shift.rewrite_reference(word));
+        let formula = parse(&expr);
+        link_dependencies(*cell_position, formula.as_ref().ok());
+
+        let old = database_get_value(cell_position);
+        if expr != *old_expr {
+            rewritten.push((*cell_position, old.clone()));
+        }
+        let cell_ref = match formula {
+            Ok(formula) => CellRef::with_formula(old.cell_value, expr, formula),
+            Err(_) => CellRef::new(old.cell_value, Some(expr)),
+        };
+        database_insert(*cell_position, cell_ref);
+    }
+
+    // Recalculate every formula
+    let cells: Vec<_> = formulas.into_iter().map(|(position, _)| position).collect();
+    recalculate(&cells);
+
+    // Deleted cells are recorded where they were, before the entries follow the shift
+    for (cell_position, old) in deleted {
+        audit_change(session, cell_position, old, None, CellValue::None);
+    }
+    audit_shift(shift);
+    audit_written(session, rewritten);
+    None
+}
+
+// Get the keys of all the cells that an expression depends on,
+// the regions of all the ranges it depends on and the names it uses
+// Names and table columns are followed to the cells or ranges they currently stand for
+fn find_dependencies(formula: &Expr) -> (Vec<(u32, u32)>, Vec<Region>, Vec<String>) {
+    let mut var_list = Vec::new();
+    let mut regions = Vec::new();
+    let mut names = Vec::new();
+    for word in formula.variables() {
+        if is_valid_name(word) {
+            names.push(word.to_string());
+        }
+        if let Some((table, _)) = split_table_column(word) {
+            names.push(table.to_string());
+        }
+        match split_reference(&resolve_name(word)).as_deref() {
+            Some([address]) => var_list.extend(address.position()),
+            Some([start, end]) => regions.push(Region::from_corners(start, end)),
+            _ => {}
+        }
+    }
+    (var_list, regions, names)
+}
+
+// Recalculate the cells that changed and every cell that depends on them
+fn recalculate(cells: &[(u32, u32)]) {
+    let started = Instant::now();
+
+    // Perform topological sorting
+    let (topological_order, cell_self_ref) = match find_topology_sort_of_dependents(cells) {
+        Ok(topological_order) => (topological_order, Vec::new()),
+        Err(CycleDetected(cell_self_ref, topological_order)) => (topological_order, cell_self_ref),
+    };
+
+    // Updating cell values in topological order
+    for cell in topological_order.iter() {
+        if let Some(formula) = database_get_value(cell).formula {
+            database_set_value(cell, evaluate_formula(&formula));
+        }
+    }
+
+    // If a self-referencing error is detected, set an error message for all error cells
+    for cell in cell_self_ref.iter() {
+        database_set_value(
+            cell,
+            CellValue::Error(format!(
+                "Error: Cell {} is self-referential",
+                pos_to_cell_id(cell)
+            )),
+        );
+    }
+
+    record_recalculation(
+        started.elapsed(),
+        topological_order.len() + cell_self_ref.len(),
+        !cell_self_ref.is_empty(),
+    );
+    CHANGED_CELLS.with(|changed| {
+        let mut changed = changed.borrow_mut();
+        changed.extend(topological_order);
+        changed.extend(cell_self_ref);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::audit::audit_entries;
+
+    // Formula or value, and value, of the changes of a cell recorded in the audit log
+    fn changes(cell: (u32, u32)) -> Vec<(Option<String>, Option<String>, CellValue, CellValue)> {
+        audit_entries(&cell)
+            .into_iter()
+            .map(|entry| {
+                (
+                    entry.old_expr,
+                    entry.new_expr,
+                    entry.old_value,
+                    entry.new_value,
+                )
+            })
+            .collect()
+    }
+
+    fn change(
+        old_expr: Option<&str>,
+        new_expr: Option<&str>,
+        old_value: CellValue,
+        new_value: CellValue,
+    ) -> (Option<String>, Option<String>, CellValue, CellValue) {
+        (
+            old_expr.map(String::from),
+            new_expr.map(String::from),
+            old_value,
+            new_value,
+        )
+    }
+
+    #[test]
+    fn moved_cells_are_audited() {
+        let session = Session::anonymous(41);
+        set_cell("A500001", "5", &session);
+        set_cell("B500001", "A500001 * 2", &session);
+        paste_cells(&[((0, 500001), (0, 500002))], true, &session);
+
+        let (five, ten) = (CellValue::Int(5), CellValue::Int(10));
+        assert_eq!(
+            changes((0, 500001)),
+            [
+                change(None, Some("5"), CellValue::None, five.clone()),
+                change(Some("5"), None, five.clone(), CellValue::None),
+            ]
+        );
+        assert_eq!(
+            changes((0, 500002)),
+            [change(None, Some("5"), CellValue::None, five)]
+        );
+        assert_eq!(
+            changes((1, 500001))[1],
+            change(Some("A500001 * 2"), Some("A500002 * 2"), ten.clone(), ten)
+        );
+        assert!(audit_entries(&(0, 500002))
+            .iter()
+            .all(|entry| entry.connection == 41));
     }
 }
@@ -0,0 +1,171 @@
+use crate::utils::database::{get_cell_argument, resolve_name};
+use crate::utils::formula::REFERENCE_ERROR;
+use crate::utils::functions::call_function;
+use crate::utils::parser::{BinaryOp, Expr, UnaryOp};
+use crate::utils::time::Duration;
+use crate::utils::value::{arithmetic, compare, CellArgument, CellValue};
+use std::cmp::Ordering;
+
+// Evaluate a parsed formula against the current contents of the spreadsheet
+// A formula must produce a single value, ranges can only be passed to functions
+pub fn evaluate_formula(expr: &Expr) -> CellValue {
+    match evaluate(expr) {
+        CellArgument::Value(value) => value,
+        _ => CellValue::Error(String::from(
+            "Error: A formula must evaluate to a single value",
+        )),
+    }
+}
+
+fn evaluate(expr: &Expr) -> CellArgument {
+    match expr {
+        Expr::Literal(value) => CellArgument::Value(value.clone()),
+        Expr::Variable(name) => variable(name),
+        Expr::Call(name, args) => {
+            let args: Vec<_> = args.iter().map(evaluate).collect();
+            CellArgument::Value(call_function(name, &args))
+        }
+        Expr::Index(base, index) => match evaluate(index) {
+            CellArgument::Value(CellValue::Int(position)) => element(evaluate(base), position),
+            CellArgument::Value(CellValue::Error(e)) => error(e),
+            _ => error(String::from("Error: An index must be a number")),
+        },
+        Expr::Unary(op, operand) => CellArgument::Value(unary(*op, evaluate(operand))),
+        Expr::Binary(BinaryOp::And, left, right) => {
+            CellArgument::Value(logical(left, right, false))
+        }
+        Expr::Binary(BinaryOp::Or, left, right) => CellArgument::Value(logical(left, right, true)),
+        Expr::Binary(op, left, right) => {
+            CellArgument::Value(binary(*op, evaluate(left), evaluate(right)))
+        }
+    }
+}
+
+fn error(message: String) -> CellArgument {
+    CellArgument::Value(CellValue::Error(message))
+}
+
+// Value of a cell, range, defined name or table column
+fn variable(name: &str) -> CellArgument {
+    if resolve_name(name) == REFERENCE_ERROR {
+        return error(String::from("Error: Invalid cell reference"));
+    }
+    match get_cell_argument(name) {
+        Some(value) => value,
+        None => error(format!("Error: Variable not found: {}", name)),
+    }
+}
+
+// Element of a range counting from 0, a row for a matrix
+fn element(base: CellArgument, position: i64) -> CellArgument {
+    let position = match usize::try_from(position) {
+        Ok(position) => position,
+        Err(_) => return error(format!("Error: Index out of range: {}", position)),
+    };
+    let found = match base {
+        CellArgument::Value(CellValue::Error(e)) => return error(e),
+        CellArgument::Value(_) => {
+            return error(String::from("Error: Only ranges can be indexed"));
+        }
+        CellArgument::Vector(vector) => vector.into_iter().nth(position).map(CellArgument::Value),
+        CellArgument::Matrix(matrix) => matrix.into_iter().nth(position).map(CellArgument::Vector),
+    };
+    found.unwrap_or_else(|| error(format!("Error: Index out of range: {}", position)))
+}
+
+// Operands must be single values, errors are passed on unchanged
+fn operand(value: CellArgument, op: &str) -> Result<CellValue, CellValue> {
+    match value {
+        CellArgument::Value(CellValue::Error(e)) => Err(CellValue::Error(e)),
+        CellArgument::Value(value) => Ok(value),
+        _ => Err(CellValue::Error(format!(
+            "Error: Cannot apply {} to a range",
+            op
+        ))),
+    }
+}
+
+// Booleans, and numbers where anything other than 0 is true
+fn truth(value: CellArgument, op: &str) -> Result<bool, CellValue> {
+    match operand(value, op)? {
+        CellValue::Bool(value) => Ok(value),
+        CellValue::Int(number) => Ok(number != 0),
+        value => Err(CellValue::Error(format!(
+            "Error: Cannot apply {} to {}",
+            op, value
+        ))),
+    }
+}
+
+fn unary(op: UnaryOp, value: CellArgument) -> CellValue {
+    let result = match op {
+        UnaryOp::Negate => match operand(value, "-") {
+            Ok(CellValue::Int(number)) => number
+                .checked_neg()
+                .map(CellValue::Int)
+                .ok_or_else(overflow),
+            Ok(CellValue::Float(number)) => Ok(CellValue::Float(-number)),
+            Ok(CellValue::Decimal(number)) => number
+                .checked_neg()
+                .map(CellValue::Decimal)
+                .ok_or_else(overflow),
+            Ok(CellValue::Duration(duration)) => duration
+                .0
+                .checked_neg()
+                .map(|seconds| CellValue::Duration(Duration(seconds)))
+                .ok_or_else(overflow),
+            Ok(value) => Err(CellValue::Error(format!(
+                "Error: Cannot apply - to {}",
+                value
+            ))),
+            Err(e) => Err(e),
+        },
+        UnaryOp::Not => truth(value, "!").map(|truth| CellValue::Bool(!truth)),
+    };
+    result.unwrap_or_else(|e| e)
+}
+
+// `&&` and `||` only evaluate their right side when the left side does not decide the result
+fn logical(left: &Expr, right: &Expr, is_or: bool) -> CellValue {
+    let op = if is_or { "||" } else { "&&" };
+    let result = truth(evaluate(left), op).and_then(|left| {
+        if left == is_or {
+            Ok(left)
+        } else {
+            truth(evaluate(right), op)
+        }
+    });
+    match result {
+        Ok(truth) => CellValue::Bool(truth),
+        Err(e) => e,
+    }
+}
+
+fn overflow() -> CellValue {
+    CellValue::Error(String::from("Error: Arithmetic overflow"))
+}
+
+fn binary(op: BinaryOp, left: CellArgument, right: CellArgument) -> CellValue {
+    let symbol = op.to_string();
+    let (left, right) = match (operand(left, &symbol), operand(right, &symbol)) {
+        (Ok(left), Ok(right)) => (left, right),
+        (Err(e), _) | (_, Err(e)) => return e,
+    };
+
+    let compared = |accepted: &[Ordering]| match compare(&left, &right) {
+        Some(ordering) => CellValue::Bool(accepted.contains(&ordering)),
+        None => CellValue::Error(format!("Error: Cannot compare {} and {}", left, right)),
+    };
+
+    // Values of different types are never equal, except numbers of the same size
+    let equal = compare(&left, &right).map_or(left == right, |ordering| ordering.is_eq());
+    match op {
+        BinaryOp::Equal => CellValue::Bool(equal),
+        BinaryOp::NotEqual => CellValue::Bool(!equal),
+        BinaryOp::Less => compared(&[Ordering::Less]),
+        BinaryOp::LessEqual => compared(&[Ordering::Less, Ordering::Equal]),
+        BinaryOp::Greater => compared(&[Ordering::Greater]),
+        BinaryOp::GreaterEqual => compared(&[Ordering::Greater, Ordering::Equal]),
+        op => arithmetic(op, &left, &right),
+    }
+}
@@ -0,0 +1,151 @@
+use std::ops::Range;
+
+// Marker written into a formula in place of a reference to a cell that no longer exists
+pub const REFERENCE_ERROR: &str = "REF_ERROR";
+
+// Operators and punctuation, longest first so that `<=` is not read as `<`
+const SYMBOLS: &[&str] = &[
+    "==", "!=", "<=", ">=", "&&", "||", "(", ")", "[", "]", ",", "+", "-", "*", "/", "%", "<", ">",
+    "!",
+];
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum TokenKind {
+    // Identifier-like word: a number, a cell or range reference, a name,
+    // a table column like `Orders.Amount` or a function name
+    Word,
+    // String literal, holding the text between the quotes with escapes resolved
+    Text(String),
+    // Operator or punctuation
+    Symbol(&'static str),
+}
+
+// Token of a formula with its byte range in the formula text
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Range<usize>,
+}
+
+// Text that could not be split into tokens, with the byte offset where it failed
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TokenError {
+    pub message: String,
+    pub offset: usize,
+}
+
+// `$` is accepted so that anchored references like `$A$1` form a single word
+fn is_word_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'$'
+}
+
+// Split a formula into tokens, skipping whitespace
+pub fn tokenize(expr: &str) -> Result<Vec<Token>, TokenError> {
+    let bytes = expr.as_bytes();
+    let mut tokens = Vec::new();
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let byte = bytes[index];
+        let start = index;
+        if byte.is_ascii_whitespace() {
+            index += 1;
+        } else if byte == b'"' {
+            // Read the whole literal, honouring backslash escapes
+            let unterminated = TokenError {
+                message: String::from("Unterminated string"),
+                offset: start,
+            };
+            let mut text = String::new();
+            let mut chars = expr[index + 1..].char_indices();
+            loop {
+                match chars.next() {
+                    Some((offset, '"')) => {
+                        index += offset + 2;
+                        break;
+                    }
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, 'n')) => text.push('\n'),
+                        Some((_, 't')) => text.push('\t'),
+                        Some((_, ch)) => text.push(ch),
+                        None => return Err(unterminated),
+                    },
+                    Some((_, ch)) => text.push(ch),
+                    None => return Err(unterminated),
+                }
+            }
+            tokens.push(Token {
+                kind: TokenKind::Text(text),
+                span: start..index,
+            });
+        } else if is_word_byte(byte) {
+            // A qualified word like `Orders.Amount` is a single word
+            while index < bytes.len()
+                && (is_word_byte(bytes[index])
+                    || (bytes[index] == b'.'
+                        && index + 1 < bytes.len()
+                        && is_word_byte(bytes[index + 1])))
+            {
+                index += 1;
+            }
+            tokens.push(Token {
+                kind: TokenKind::Word,
+                span: start..index,
+            });
+        } else {
+            match SYMBOLS
+                .iter()
+                .find(|symbol| expr[index..].starts_with(**symbol))
+            {
+                Some(symbol) => {
+                    index += symbol.len();
+                    tokens.push(Token {
+                        kind: TokenKind::Symbol(symbol),
+                        span: start..index,
+                    });
+                }
+                None => {
+                    let ch = expr[index..].chars().next().unwrap_or_default();
+                    return Err(TokenError {
+                        message: format!("Unexpected character `{}`", ch),
+                        offset: start,
+                    });
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// Byte ranges of the words of a formula that are not function names
+// A formula that cannot be split into tokens has no words
+fn find_words(expr: &str) -> Vec<Range<usize>> {
+    let tokens = tokenize(expr).unwrap_or_default();
+    tokens
+        .iter()
+        .enumerate()
+        .filter(|(index, token)| {
+            token.kind == TokenKind::Word
+                && tokens
+                    .get(index + 1)
+                    .is_none_or(|next| next.kind != TokenKind::Symbol("("))
+        })
+        .map(|(_, token)| token.span.clone())
+        .collect()
+}
+
+// Replace words of a formula for which `rewrite` returns a new text,
+// leaving literals, operators and whitespace untouched
+pub fn rewrite_words(expr: &str, mut rewrite: impl FnMut(&str) -> Option<String>) -> String {
+    let mut result = String::with_capacity(expr.len());
+    let mut last = 0;
+    for word in find_words(expr) {
+        if let Some(replacement) = rewrite(&expr[word.clone()]) {
+            result.push_str(&expr[last..word.start]);
+            result.push_str(&replacement);
+            last = word.end;
+        }
+    }
+    result.push_str(&expr[last..]);
+    result
+}
@@ -0,0 +1,720 @@
+use crate::utils::decimal::{Decimal, MAX_SCALE};
+use crate::utils::parser::BinaryOp;
+use crate::utils::time::Date;
+use crate::utils::value::{self, arithmetic, CellArgument, CellValue};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+// Spreadsheet function called with the arguments of a formula
+type NativeFunction = fn(&[CellArgument]) -> CellValue;
+
+// Functions available to every formula, with the least and the most arguments each accepts
+const FUNCTIONS: &[(&str, usize, usize, NativeFunction)] = &[
+    // The functions `rsheet_lib` provides
+    ("sum", 1, usize::MAX, summer),
+    ("sleep_then", 2, 2, sleep_then),
+    ("SUM", 1, usize::MAX, sum),
+    ("AVERAGE", 1, usize::MAX, average),
+    ("MIN", 1, usize::MAX, min),
+    ("MAX", 1, usize::MAX, max),
+    ("COUNT", 1, usize::MAX, count),
+    ("COUNTIF", 2, 2, count_if),
+    ("IF", 2, 3, if_then),
+    ("ROUND", 1, 2, round),
+    ("CONCAT", 1, usize::MAX, concat),
+    ("LOOKUP", 2, 3, lookup),
+    ("VLOOKUP", 3, 4, vlookup),
+    ("INDEX", 2, 3, index),
+    ("MATCH", 2, 3, match_position),
+    ("DECIMAL", 2, 2, decimal),
+    ("FLOAT", 1, 1, float),
+    ("DATE", 3, 3, date),
+    ("YEAR", 1, 1, year),
+    ("MONTH", 1, 1, month),
+    ("DAY", 1, 1, day),
+];
+
+// Kind of argument a user-defined function accepts
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ArgumentType {
+    // A single cell or a value computed by the formula
+    Value,
+    // A range within a single row or column
+    Vector,
+    // A range over several rows and columns
+    Matrix,
+    // Any of the above
+    Any,
+}
+
+impl ArgumentType {
+    fn accepts(&self, arg: &CellArgument) -> bool {
+        matches!(
+            (self, arg),
+            (ArgumentType::Any, _)
+                | (ArgumentType::Value, CellArgument::Value(_))
+                | (ArgumentType::Vector, CellArgument::Vector(_))
+                | (ArgumentType::Matrix, CellArgument::Matrix(_))
+        )
+    }
+}
+
+impl Display for ArgumentType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ArgumentType::Value => write!(f, "a single value"),
+            ArgumentType::Vector => write!(f, "a row or column range"),
+            ArgumentType::Matrix => write!(f, "a range of rows and columns"),
+            ArgumentType::Any => write!(f, "any value or range"),
+        }
+    }
+}
+
+type UserClosure = dyn Fn(&[CellArgument]) -> CellValue + Send;
+
+// Rust closure callable from formulas under `name`, taking one argument for every declared type
+// Arguments of the wrong kind are rejected with an error before the closure is called
+pub struct UserFunction {
+    name: String,
+    arguments: Vec<ArgumentType>,
+    function: Box<UserClosure>,
+}
+
+impl UserFunction {
+    pub fn new(
+        name: &str,
+        arguments: Vec<ArgumentType>,
+        function: impl Fn(&[CellArgument]) -> CellValue + Send + 'static,
+    ) -> Self {
+        UserFunction {
+            name: name.to_string(),
+            arguments,
+            function: Box::new(function),
+        }
+    }
+
+    fn call(&self, args: &[CellArgument]) -> CellValue {
+        for (position, (expected, arg)) in self.arguments.iter().zip(args).enumerate() {
+            if !expected.accepts(arg) {
+                return error(
+                    &self.name,
+                    &format!("argument {} must be {}", position + 1, expected),
+                );
+            }
+        }
+        (self.function)(args)
+    }
+}
+
+// Source of the user-defined functions an embedder passes to `start_server`
+pub trait FunctionRegistry {
+    fn functions(self) -> Vec<UserFunction>;
+}
+
+impl FunctionRegistry for Vec<UserFunction> {
+    fn functions(self) -> Vec<UserFunction> {
+        self
+    }
+}
+
+// No user-defined functions
+impl FunctionRegistry for () {
+    fn functions(self) -> Vec<UserFunction> {
+        Vec::new()
+    }
+}
+
+thread_local! {
+    // Formulas are only evaluated by the worker thread, which holds the user-defined functions
+    static USER_FUNCTIONS: RefCell<HashMap<String, UserFunction>> = RefCell::new(HashMap::new());
+}
+
+// Make user-defined functions callable from formulas evaluated on the current thread
+// A function named like a built-in one replaces it
+pub fn register_user_functions(functions: Vec<UserFunction>) {
+    USER_FUNCTIONS.with(|user_functions| {
+        let mut user_functions = user_functions.borrow_mut();
+        for function in functions {
+            user_functions.insert(function.name.clone(), function);
+        }
+    });
+}
+
+// Call a function of a formula with its evaluated arguments
+// An error passed as an argument is the result of the call
+pub fn call_function(name: &str, args: &[CellArgument]) -> CellValue {
+    for arg in args {
+        if let CellArgument::Value(CellValue::Error(e)) = arg {
+            return CellValue::Error(e.clone());
+        }
+    }
+
+    let result = USER_FUNCTIONS.with(|user_functions| {
+        let user_functions = user_functions.borrow();
+        let function = user_functions.get(name)?;
+        Some(match function.arguments.len() {
+            arity if arity == args.len() => function.call(args),
+            arity => arity_error(name, arity, arity),
+        })
+    });
+    if let Some(result) = result {
+        return result;
+    }
+
+    match FUNCTIONS
+        .iter()
+        .find(|(function_name, ..)| *function_name == name)
+    {
+        Some((_, min_args, max_args, function))
+            if (*min_args..=*max_args).contains(&args.len()) =>
+        {
+            function(args)
+        }
+        Some((_, min_args, max_args, _)) => arity_error(name, *min_args, *max_args),
+        None => CellValue::Error(format!("Error: Function not found: {}", name)),
+    }
+}
+
+fn arity_error(name: &str, min_args: usize, max_args: usize) -> CellValue {
+    let expected = match (min_args, max_args) {
+        (min_args, usize::MAX) => format!("at least {}", min_args),
+        (min_args, max_args) if min_args == max_args => min_args.to_string(),
+        (min_args, max_args) => format!("{} to {}", min_args, max_args),
+    };
+    CellValue::Error(format!("Error: {} takes {} arguments", name, expected))
+}
+
+// `sum` as `rsheet_lib` provides it, adding numbers and rejecting anything else,
+// blank cells included, `SUM` skips them
+fn summer(args: &[CellArgument]) -> CellValue {
+    let mut numbers = Vec::new();
+    for value in values(args) {
+        match value {
+            value if value.is_number() => numbers.push(value),
+            CellValue::Error(_) => return value.clone(),
+            value => return CellValue::Error(format!("Error: Unknown value: {}", value)),
+        }
+    }
+    total(&numbers)
+}
+
+// `sleep_then(millis, value)`, waiting before giving back the value
+fn sleep_then(args: &[CellArgument]) -> CellValue {
+    let millis = match integer(&args[0]) {
+        Some(millis) => millis,
+        None => return error("sleep_then", "milliseconds must be a number"),
+    };
+    std::thread::sleep(Duration::from_millis(millis.max(0) as u64));
+    match scalar(&args[1]) {
+        Some(value) => value.clone(),
+        None => error("sleep_then", "value must be a single value"),
+    }
+}
+
+// All values of the arguments, ranges flattened row by row
+fn values(args: &[CellArgument]) -> Vec<&CellValue> {
+    let mut values = Vec::new();
+    for arg in args {
+        match arg {
+            CellArgument::Value(value) => values.push(value),
+            CellArgument::Vector(vector) => values.extend(vector),
+            CellArgument::Matrix(matrix) => values.extend(matrix.iter().flatten()),
+        }
+    }
+    values
+}
+
+// Values of the arguments passing `keep`, blank cells and text are typically skipped
+// Returns the first error found instead
+fn filtered(
+    args: &[CellArgument],
+    keep: fn(&CellValue) -> bool,
+) -> Result<Vec<&CellValue>, CellValue> {
+    let mut kept = Vec::new();
+    for value in values(args) {
+        match value {
+            CellValue::Error(_) => return Err(value.clone()),
+            value if keep(value) => kept.push(value),
+            _ => {}
+        }
+    }
+    Ok(kept)
+}
+
+// Integers and floats among the values of the arguments
+fn numbers(args: &[CellArgument]) -> Result<Vec<&CellValue>, CellValue> {
+    filtered(args, CellValue::is_number)
+}
+
+// Sum of numbers, an integer for integers, an exact decimal when decimals are among them
+// and a float when floats are
+fn total(numbers: &[&CellValue]) -> CellValue {
+    numbers.iter().fold(CellValue::Int(0), |total, number| {
+        arithmetic(BinaryOp::Add, &total, number)
+    })
+}
+
+// A single value argument, such as the condition of `IF` or the digits of `ROUND`
+fn scalar(arg: &CellArgument) -> Option<&CellValue> {
+    match arg {
+        CellArgument::Value(value) => Some(value),
+        _ => None,
+    }
+}
+
+fn integer(arg: &CellArgument) -> Option<i64> {
+    match scalar(arg)? {
+        CellValue::Int(number) => Some(*number),
+        _ => None,
+    }
+}
+
+// Ranges as rows of values, a single column or row range is a column
+fn rows(arg: &CellArgument) -> Vec<Vec<CellValue>> {
+    match arg {
+        CellArgument::Value(value) => vec![vec![value.clone()]],
+        CellArgument::Vector(vector) => vector.iter().map(|value| vec![value.clone()]).collect(),
+        CellArgument::Matrix(matrix) => matrix.clone(),
+    }
+}
+
+fn vector(arg: &CellArgument) -> Vec<CellValue> {
+    match arg {
+        CellArgument::Value(value) => vec![value.clone()],
+        CellArgument::Vector(vector) => vector.clone(),
+        CellArgument::Matrix(matrix) => matrix.concat(),
+    }
+}
+
+// Values compare as in formulas, except that text is compared ignoring case
+fn compare(a: &CellValue, b: &CellValue) -> Option<Ordering> {
+    match (a, b) {
+        (CellValue::String(a), CellValue::String(b)) => {
+            Some(a.to_lowercase().cmp(&b.to_lowercase()))
+        }
+        (a, b) => value::compare(a, b),
+    }
+}
+
+fn error(function: &str, message: &str) -> CellValue {
+    CellValue::Error(format!("Error: {}: {}", function, message))
+}
+
+fn sum(args: &[CellArgument]) -> CellValue {
+    match numbers(args) {
+        Ok(numbers) => total(&numbers),
+        Err(e) => e,
+    }
+}
+
+fn average(args: &[CellArgument]) -> CellValue {
+    match numbers(args) {
+        Ok(numbers) if numbers.is_empty() => error("AVERAGE", "division by zero"),
+        Ok(numbers) => match total(&numbers) {
+            total @ CellValue::Decimal(_) => arithmetic(
+                BinaryOp::Divide,
+                &total,
+                &CellValue::Int(numbers.len() as i64),
+            ),
+            total => match total.to_float() {
+                Some(total) => CellValue::Float(total / numbers.len() as f64),
+                None => total,
+            },
+        },
+        Err(e) => e,
+    }
+}
+
+// Smallest or largest of the numbers, dates, date-times and durations,
+// values that cannot be compared with each other are an error
+// With nothing to compare, as when every cell is blank, the result is 0 as in other spreadsheets
+fn extreme(function: &str, args: &[CellArgument], wanted: Ordering) -> CellValue {
+    let values = match filtered(args, CellValue::is_orderable) {
+        Ok(values) => values,
+        Err(e) => return e,
+    };
+    let mut best = match values.first() {
+        Some(value) => *value,
+        None => return CellValue::Int(0),
+    };
+    for value in values {
+        match compare(value, best) {
+            Some(ordering) if ordering == wanted => best = value,
+            Some(_) => {}
+            None => return error(function, "values cannot be compared"),
+        }
+    }
+    best.clone()
+}
+
+fn min(args: &[CellArgument]) -> CellValue {
+    extreme("MIN", args, Ordering::Less)
+}
+
+fn max(args: &[CellArgument]) -> CellValue {
+    extreme("MAX", args, Ordering::Greater)
+}
+
+// Numbers, dates, date-times and durations are counted
+fn count(args: &[CellArgument]) -> CellValue {
+    match filtered(args, CellValue::is_orderable) {
+        Ok(values) => CellValue::Int(values.len() as i64),
+        Err(e) => e,
+    }
+}
+
+// `COUNTIF(range, criterion)`, where the criterion is a value to match
+// or text like ">5", "<=10" or "<>done" comparing against a number or text
+fn count_if(args: &[CellArgument]) -> CellValue {
+    let criterion = match scalar(&args[1]) {
+        Some(criterion) => criterion,
+        None => return error("COUNTIF", "criterion must be a single value"),
+    };
+
+    let (accepted, target): (&[Ordering], CellValue) = match criterion {
+        CellValue::String(text) => {
+            let operators: [(&str, &[Ordering]); 6] = [
+                ("<>", &[Ordering::Less, Ordering::Greater]),
+                ("<=", &[Ordering::Less, Ordering::Equal]),
+                (">=", &[Ordering::Greater, Ordering::Equal]),
+                ("<", &[Ordering::Less]),
+                (">", &[Ordering::Greater]),
+                ("=", &[Ordering::Equal]),
+            ];
+            let (accepted, operand) = operators
+                .iter()
+                .find_map(|(operator, accepted)| {
+                    text.strip_prefix(operator)
+                        .map(|operand| (*accepted, operand))
+                })
+                .unwrap_or((&[Ordering::Equal], text.as_str()));
+            let operand = operand.trim();
+            let target = match (
+                operand.parse::<i64>(),
+                Decimal::parse(operand),
+                operand.parse::<f64>(),
+            ) {
+                (Ok(number), _, _) => CellValue::Int(number),
+                (_, Some(number), _) => CellValue::Decimal(number),
+                (_, _, Ok(number)) => CellValue::Float(number),
+                _ => CellValue::parse_literal(operand)
+                    .unwrap_or_else(|| CellValue::String(operand.to_string())),
+            };
+            (accepted, target)
+        }
+        CellValue::Error(_) => return criterion.clone(),
+        criterion => (&[Ordering::Equal], criterion.clone()),
+    };
+
+    // `<>` also counts values that cannot be compared with the target
+    let not_equal = !accepted.contains(&Ordering::Equal) && accepted.len() == 2;
+    let mut matches = 0;
+    for value in values(&args[..1]) {
+        if let CellValue::Error(_) = value {
+            return value.clone();
+        }
+        let is_match = match compare(value, &target) {
+            Some(ordering) => accepted.contains(&ordering),
+            None => not_equal,
+        };
+        if is_match {
+            matches += 1;
+        }
+    }
+    CellValue::Int(matches)
+}
+
+// `IF(condition, then, else)`, a condition is true when it is true or a non-zero number
+// Without an else branch a false condition gives a blank value
+fn if_then(args: &[CellArgument]) -> CellValue {
+    let condition = match scalar(&args[0]) {
+        Some(CellValue::Bool(condition)) => *condition,
+        Some(CellValue::Int(number)) => *number != 0,
+        Some(CellValue::Error(e)) => return CellValue::Error(e.clone()),
+        _ => return error("IF", "condition must be a boolean or a number"),
+    };
+    let branch = if condition { args.get(1) } else { args.get(2) };
+    match branch {
+        Some(CellArgument::Value(value)) => value.clone(),
+        Some(_) => error("IF", "result must be a single value"),
+        None => CellValue::None,
+    }
+}
+
+// `ROUND(number, digits)`, negative digits round to tens, hundreds and so on
+// A decimal keeps exactly `digits` digits after the point
+fn round(args: &[CellArgument]) -> CellValue {
+    let digits = match args.get(1).map(integer) {
+        None => 0,
+        Some(Some(digits)) => digits,
+        Some(None) => return error("ROUND", "digits must be a number"),
+    };
+    let number = match scalar(&args[0]) {
+        Some(CellValue::Int(number)) => *number,
+        Some(CellValue::Float(number)) => {
+            let scale = 10f64.powi(digits.clamp(-308, 308) as i32);
+            let rounded = (number * scale).round() / scale;
+            return match digits {
+                0 => CellValue::Int(rounded as i64),
+                _ => CellValue::Float(rounded),
+            };
+        }
+        Some(CellValue::Decimal(number)) if digits > 0 => {
+            return match number.rescale(digits.min(MAX_SCALE as i64) as u32) {
+                Some(rounded) => CellValue::Decimal(rounded),
+                None => error("ROUND", "number too large"),
+            };
+        }
+        Some(CellValue::Decimal(number)) => match number.to_int() {
+            Some(number) => number,
+            None => return error("ROUND", "number too large"),
+        },
+        Some(CellValue::Error(e)) => return CellValue::Error(e.clone()),
+        _ => return error("ROUND", "argument must be a number"),
+    };
+    if digits >= 0 {
+        return CellValue::Int(number);
+    }
+
+    match 10i64.checked_pow(digits.unsigned_abs() as u32) {
+        Some(unit) => {
+            let rounded = (number as f64 / unit as f64).round() as i64;
+            match rounded.checked_mul(unit) {
+                Some(rounded) => CellValue::Int(rounded),
+                None => CellValue::Int(0),
+            }
+        }
+        None => CellValue::Int(0),
+    }
+}
+
+fn concat(args: &[CellArgument]) -> CellValue {
+    let mut text = String::new();
+    for value in values(args) {
+        match value {
+            CellValue::Error(_) => return value.clone(),
+            value => text.push_str(&value.to_text()),
+        }
+    }
+    CellValue::String(text)
+}
+
+// Position of a value in a list, for the match types of `MATCH`:
+// 1 finds the largest value not above it in an ascending list,
+// 0 the first equal value and -1 the smallest value not below it in a descending list
+fn find_position(target: &CellValue, list: &[CellValue], match_type: i64) -> Option<usize> {
+    let mut found = None;
+    for (position, value) in list.iter().enumerate() {
+        match (compare(value, target), match_type) {
+            (Some(Ordering::Equal), _) => return Some(position),
+            (Some(Ordering::Less), 1) | (Some(Ordering::Greater), -1) => found = Some(position),
+            (Some(_), 1) | (Some(_), -1) => break,
+            _ => {}
+        }
+    }
+    found
+}
+
+// `LOOKUP(value, lookup_range, result_range)`, searching an ascending range
+// for the largest value not above `value`
+fn lookup(args: &[CellArgument]) -> CellValue {
+    let target = match scalar(&args[0]) {
+        Some(CellValue::Error(e)) => return CellValue::Error(e.clone()),
+        Some(target) => target,
+        None => return error("LOOKUP", "lookup value must be a single value"),
+    };
+    let list = vector(&args[1]);
+    let results = args.get(2).map(vector).unwrap_or_else(|| list.clone());
+    match find_position(target, &list, 1).and_then(|position| results.get(position)) {
+        Some(value) => value.clone(),
+        None => error("LOOKUP", "value not found"),
+    }
+}
+
+// `VLOOKUP(value, table, column, approximate)`, searching the first column of a table
+// and returning the value in the given column (counting from 1) of the matching row
+// Exact matches only when `approximate` is 0
+fn vlookup(args: &[CellArgument]) -> CellValue {
+    let target = match scalar(&args[0]) {
+        Some(CellValue::Error(e)) => return CellValue::Error(e.clone()),
+        Some(target) => target,
+        None => return error("VLOOKUP", "lookup value must be a single value"),
+    };
+    let table = rows(&args[1]);
+    let column = match integer(&args[2]) {
+        Some(column) if column >= 1 => column as usize - 1,
+        _ => return error("VLOOKUP", "column must be a positive number"),
+    };
+    let match_type = match args.get(3).map(integer) {
+        None => 1,
+        Some(Some(0)) => 0,
+        Some(Some(_)) => 1,
+        Some(None) => return error("VLOOKUP", "approximate must be a number"),
+    };
+
+    let keys: Vec<_> = table
+        .iter()
+        .map(|row| row.first().cloned().unwrap_or(CellValue::None))
+        .collect();
+    match find_position(target, &keys, match_type) {
+        Some(position) => match table[position].get(column) {
+            Some(value) => value.clone(),
+            None => error("VLOOKUP", "column out of range"),
+        },
+        None => error("VLOOKUP", "value not found"),
+    }
+}
+
+// `INDEX(range, row, column)`, the value at the given row and column of a range counting from 1
+fn index(args: &[CellArgument]) -> CellValue {
+    let row = match integer(&args[1]) {
+        Some(row) if row >= 1 => row as usize - 1,
+        _ => return error("INDEX", "row must be a positive number"),
+    };
+    let column = match args.get(2).map(integer) {
+        None => 0,
+        Some(Some(column)) if column >= 1 => column as usize - 1,
+        Some(_) => return error("INDEX", "column must be a positive number"),
+    };
+    match rows(&args[0])
+        .get(row)
+        .and_then(|values| values.get(column))
+    {
+        Some(value) => value.clone(),
+        None => error("INDEX", "position out of range"),
+    }
+}
+
+// `MATCH(value, range, match_type)`, the position of a value in a range counting from 1
+fn match_position(args: &[CellArgument]) -> CellValue {
+    let target = match scalar(&args[0]) {
+        Some(CellValue::Error(e)) => return CellValue::Error(e.clone()),
+        Some(target) => target,
+        None => return error("MATCH", "lookup value must be a single value"),
+    };
+    let match_type = match args.get(2).map(integer) {
+        None => 1,
+        Some(Some(match_type)) => match_type.signum(),
+        Some(None) => return error("MATCH", "match type must be a number"),
+    };
+    match find_position(target, &vector(&args[1]), match_type) {
+        Some(position) => CellValue::Int(position as i64 + 1),
+        None => error("MATCH", "value not found"),
+    }
+}
+
+// `DECIMAL(number, places)`, the number as a decimal with `places` digits after the point
+fn decimal(args: &[CellArgument]) -> CellValue {
+    let places = match integer(&args[1]) {
+        Some(places) if (0..=MAX_SCALE as i64).contains(&places) => places as u32,
+        _ => return error("DECIMAL", "places must be a number from 0 to 18"),
+    };
+    let number = match scalar(&args[0]) {
+        Some(CellValue::Float(number)) => Decimal::from_f64(*number, places),
+        Some(CellValue::Error(e)) => return CellValue::Error(e.clone()),
+        Some(value) => match value.to_decimal() {
+            Some(number) => number.rescale(places),
+            None => return error("DECIMAL", "argument must be a number"),
+        },
+        None => return error("DECIMAL", "argument must be a number"),
+    };
+    match number {
+        Some(number) => CellValue::Decimal(number),
+        None => error("DECIMAL", "number too large"),
+    }
+}
+
+// `FLOAT(number)`, the nearest floating point number
+fn float(args: &[CellArgument]) -> CellValue {
+    match scalar(&args[0]).and_then(CellValue::to_float) {
+        Some(number) => CellValue::Float(number),
+        None => error("FLOAT", "argument must be a number"),
+    }
+}
+
+// `DATE(year, month, day)`
+fn date(args: &[CellArgument]) -> CellValue {
+    let parts: Option<Vec<i64>> = args.iter().map(integer).collect();
+    let (year, month, day) = match parts.as_deref() {
+        Some(&[year, month, day]) => (year, month, day),
+        _ => return error("DATE", "year, month and day must be numbers"),
+    };
+    let date = match (u32::try_from(month), u32::try_from(day)) {
+        (Ok(month), Ok(day)) => Date::from_ymd(year, month, day),
+        _ => None,
+    };
+    match date {
+        Some(date) => CellValue::Date(date),
+        None => error("DATE", "no such date"),
+    }
+}
+
+// Year, month and day of a date or date-time
+fn date_part(function: &str, args: &[CellArgument]) -> Result<(i64, u32, u32), CellValue> {
+    match scalar(&args[0]) {
+        Some(CellValue::Date(date)) => Ok(date.year_month_day()),
+        Some(CellValue::DateTime(moment)) => Ok(moment.date().year_month_day()),
+        _ => Err(error(function, "argument must be a date")),
+    }
+}
+
+fn year(args: &[CellArgument]) -> CellValue {
+    match date_part("YEAR", args) {
+        Ok((year, _, _)) => CellValue::Int(year),
+        Err(e) => e,
+    }
+}
+
+fn month(args: &[CellArgument]) -> CellValue {
+    match date_part("MONTH", args) {
+        Ok((_, month, _)) => CellValue::Int(month as i64),
+        Err(e) => e,
+    }
+}
+
+fn day(args: &[CellArgument]) -> CellValue {
+    match date_part("DAY", args) {
+        Ok((_, _, day)) => CellValue::Int(day as i64),
+        Err(e) => e,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(values: &[CellValue]) -> CellArgument {
+        CellArgument::Vector(values.to_vec())
+    }
+
+    #[test]
+    fn only_sum_skips_blank_cells() {
+        let args = [column(&[
+            CellValue::Int(1),
+            CellValue::None,
+            CellValue::Int(2),
+        ])];
+        assert!(matches!(summer(&args), CellValue::Error(_)));
+        assert_eq!(sum(&args), CellValue::Int(3));
+    }
+
+    #[test]
+    fn min_and_max_of_blank_cells() {
+        let args = [column(&[CellValue::None, CellValue::None])];
+        assert_eq!(min(&args), CellValue::Int(0));
+        assert_eq!(max(&args), CellValue::Int(0));
+    }
+
+    #[test]
+    fn summer_rejects_text() {
+        let args = [column(&[
+            CellValue::Int(1),
+            CellValue::String(String::from("a")),
+        ])];
+        assert!(matches!(summer(&args), CellValue::Error(_)));
+        assert_eq!(sum(&args), CellValue::Int(1));
+    }
+}
@@ -0,0 +1,478 @@
+use crate::utils::auth::{Access, Auth, Session};
+use crate::utils::command::{parse_command, Response};
+use crate::utils::connection_manager::next_connection_id;
+use crate::utils::database::{
+    database_get_value, pos_to_cell_id, reference_region, resolve_name, split_cell_id,
+    MAX_RANGE_CELLS,
+};
+use crate::utils::dependency_manager::find_direct_dependents;
+use crate::utils::engine::Transaction;
+use crate::utils::json_protocol::{classify_error, typed_value};
+use crate::utils::metrics::{open_connection, render_metrics};
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// Largest request body accepted, an expression for `PUT /cells/<cell>`
+const MAX_BODY_LENGTH: usize = 64 * 1024;
+
+// Longest line accepted in the head of a request, and most lines in it
+const MAX_LINE_LENGTH: u64 = 8 * 1024;
+const MAX_HEAD_LINES: usize = 100;
+
+// Time a client may leave a connection silent while sending its request
+pub const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Most connections a front-end serves at once, others are turned away as busy
+const MAX_CONNECTIONS: usize = 256;
+
+// Answer to a connection turned away because every slot is taken
+const BUSY_RESPONSE: &str =
+    "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+// Content type of the Prometheus text exposition format
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+// REST front-end to the spreadsheet:
+//   GET /cells/A1             value of a cell
+//   PUT /cells/A1             set a cell to the expression in the body, `?force=true` as `set --force`
+//   GET /ranges/A1_C5         values of a range, row by row
+//   GET /cells/A1/dependents  cells whose formulas use a cell directly
+//   GET /metrics              counters and histograms in the Prometheus text format
+// Every other response is JSON, values are typed as in the JSON-lines protocol
+// With authentication, requests carry an `Authorization: Bearer <token>` or `Basic` header
+pub fn serve_http(
+    listener: TcpListener,
+    transactions_sender: Sender<Transaction>,
+    auth: Option<Arc<Auth>>,
+) {
+    let open = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming().flatten() {
+        let slot = match ConnectionSlot::take(&open) {
+            Some(slot) => slot,
+            None => {
+                ConnectionSlot::refuse(stream);
+                continue;
+            }
+        };
+        let transactions_sender = transactions_sender.clone();
+        let auth = auth.clone();
+        thread::spawn(move || {
+            let _slot = slot;
+            let _ = handle_connection(stream, &transactions_sender, auth.as_deref());
+        });
+    }
+}
+
+// A connection counted against `MAX_CONNECTIONS` of its front-end until this is dropped
+pub struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    // Count a new connection, `None` if the front-end already serves as many as it may
+    pub fn take(open: &Arc<AtomicUsize>) -> Option<Self> {
+        open.fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+            (count < MAX_CONNECTIONS).then_some(count + 1)
+        })
+        .ok()
+        .map(|_| ConnectionSlot(Arc::clone(open)))
+    }
+
+    // Turn a connection away without reading from it
+    pub fn refuse(mut stream: TcpStream) {
+        let _ = stream.write_all(BUSY_RESPONSE.as_bytes());
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// Read the head of a request, its request line and headers, up to the blank line ending it
+// Lines are returned without their line ending; longer lines than `MAX_LINE_LENGTH`
+// and heads of more than `MAX_HEAD_LINES` lines are rejected rather than buffered
+pub fn read_head(reader: &mut impl BufRead) -> io::Result<Vec<String>> {
+    let invalid = |message: &str| io::Error::new(ErrorKind::InvalidData, message);
+    let mut lines = Vec::new();
+    loop {
+        let mut line = Vec::new();
+        let read = (&mut *reader)
+            .take(MAX_LINE_LENGTH)
+            .read_until(b'\n', &mut line)?;
+        if read == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        if line.last() != Some(&b'\n') && read as u64 == MAX_LINE_LENGTH {
+            return Err(invalid("Request line too long"));
+        }
+        let line = String::from_utf8(line).map_err(|_| invalid("Request is not valid UTF-8"))?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok(lines);
+        }
+        if lines.len() == MAX_HEAD_LINES {
+            return Err(invalid("Too many headers"));
+        }
+        lines.push(line.to_string());
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: String,
+    authorization: Option<String>,
+    body: String,
+}
+
+// Answer a single request, the connection is closed afterwards
+fn handle_connection(
+    stream: TcpStream,
+    transactions_sender: &Sender<Transaction>,
+    auth: Option<&Auth>,
+) -> io::Result<()> {
+    let _open = open_connection();
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let (status, body) = match read_request(&mut BufReader::new(stream)) {
+        Ok(request) => match authorize(&request, auth) {
+            Some(_) if request.method == "GET" && request.path == "/metrics" => {
+                (200, Value::String(render_metrics()))
+            }
+            Some(session) => route(&request, transactions_sender, &session),
+            None => (
+                401,
+                error_body("unauthenticated", "Missing or invalid credentials"),
+            ),
+        },
+        Err(message) => (400, error_body("invalid_request", &message)),
+    };
+
+    // A `204 No Content` response has no body, metrics are sent as the text they are
+    let (content_type, body) = match (status, body) {
+        (204, _) => (JSON_CONTENT_TYPE, String::new()),
+        (_, Value::String(text)) => (METRICS_CONTENT_TYPE, text),
+        (_, body) => (JSON_CONTENT_TYPE, body.to_string()),
+    };
+    let challenge = match status {
+        401 => "WWW-Authenticate: Basic realm=\"rsheet\"\r\n",
+        _ => "",
+    };
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        content_type,
+        body.len(),
+        challenge,
+        body
+    )?;
+    writer.flush()
+}
+
+fn read_request(reader: &mut BufReader<TcpStream>) -> Result<HttpRequest, String> {
+    let head = read_head(reader).map_err(|e| e.to_string())?;
+    let (line, headers) = match head.split_first() {
+        Some((line, headers)) => (line, headers),
+        None => return Err(String::from("Malformed request line")),
+    };
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Err(String::from("Malformed request line")),
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), query.to_string()),
+        None => (target, String::new()),
+    };
+
+    let mut content_length = 0;
+    let mut authorization = None;
+    for header in headers {
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| String::from("Invalid Content-Length"))?;
+            } else if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+    }
+    if content_length > MAX_BODY_LENGTH {
+        return Err(String::from("Request body too large"));
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).map_err(|e| e.to_string())?;
+    let body = String::from_utf8(body).map_err(|_| String::from("Body is not valid UTF-8"))?;
+    Ok(HttpRequest {
+        method,
+        path,
+        query,
+        authorization,
+        body,
+    })
+}
+
+// Session of a request, with full access when no authentication is configured
+// Every request counts as a connection of its own
+fn authorize(request: &HttpRequest, auth: Option<&Auth>) -> Option<Session> {
+    let connection = next_connection_id();
+    match auth {
+        Some(auth) => {
+            let (user, access) = auth.authorize(request.authorization.as_deref()?)?;
+            Some(Session {
+                connection,
+                user: Some(user),
+                access,
+            })
+        }
+        None => Some(Session::anonymous(connection)),
+    }
+}
+
+fn route(
+    request: &HttpRequest,
+    transactions_sender: &Sender<Transaction>,
+    session: &Session,
+) -> (u16, Value) {
+    let segments: Option<Vec<_>> = request
+        .path
+        .trim_matches('/')
+        .split('/')
+        .map(percent_decode)
+        .collect();
+    let segments = match segments {
+        Some(segments) => segments,
+        None => return (400, error_body("invalid_request", "Invalid path")),
+    };
+    let segments: Vec<_> = segments.iter().map(String::as_str).collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        // Cells are checked before they go into a command, where they could read as its options
+        ("GET" | "PUT", ["cells", cell]) if split_cell_id(cell).is_none() => invalid_cell(cell),
+        ("GET", ["cells", cell]) => run(&format!("get {}", cell), transactions_sender, session),
+        ("PUT", ["cells", cell]) => {
+            let force = request
+                .query
+                .split('&')
+                .any(|parameter| parameter == "force=true");
+            let expr = request.body.trim();
+            let command = if force {
+                format!("set --force {} {}", cell, expr)
+            } else {
+                format!("set {} {}", cell, expr)
+            };
+            run(&command, transactions_sender, session)
+        }
+        ("GET", ["ranges", range]) => get_range(range, &session.access),
+        ("GET", ["cells", cell, "dependents"]) => get_dependents(cell, &session.access),
+        (_, ["cells", _])
+        | (_, ["ranges", _])
+        | (_, ["cells", _, "dependents"])
+        | (_, ["metrics"]) => (405, error_body("method_not_allowed", "Method not allowed")),
+        _ => (404, error_body("not_found", "Not found")),
+    }
+}
+
+// Run a command as a connection would, so sets go through the engine thread
+fn run(
+    command: &str,
+    transactions_sender: &Sender<Transaction>,
+    session: &Session,
+) -> (u16, Value) {
+    match parse_command(command).execute(transactions_sender, session) {
+        None => (204, Value::Null),
+        Some(Response::Value(cell, value)) => {
+            (200, json!({"cell": cell, "value": typed_value(&value)}))
+        }
+        Some(Response::Error(e)) => {
+            let (code, message) = classify_error(&e);
+            let status = match code {
+                "invalid_request" | "invalid_key" | "invalid_formula" | "invalid_destination" => {
+                    400
+                }
+                "range_too_large" => 413,
+                "permission_denied" => 403,
+                "protected" => 409,
+                _ => 422,
+            };
+            (status, error_body(code, message))
+        }
+    }
+}
+
+// Ranges of more than `MAX_RANGE_CELLS` cells are refused before their cells are listed
+fn get_range(range: &str, access: &Access) -> (u16, Value) {
+    let region = match reference_region(&resolve_name(range)) {
+        Some(region) => region,
+        None => {
+            return (
+                400,
+                error_body("invalid_key", &format!("Invalid range: {}", range)),
+            )
+        }
+    };
+    if region.cell_count() > MAX_RANGE_CELLS {
+        let message = format!(
+            "Range too large: {}, more than {} cells",
+            range, MAX_RANGE_CELLS
+        );
+        return (413, error_body("range_too_large", &message));
+    }
+    if !access.can_read_region(&region) {
+        return (403, error_body("permission_denied", "Permission denied"));
+    }
+    let values: Vec<Vec<Value>> = region
+        .rows()
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|position| typed_value(&database_get_value(position).cell_value))
+                .collect()
+        })
+        .collect();
+    (200, json!({"range": range, "rows": values}))
+}
+
+fn get_dependents(cell: &str, access: &Access) -> (u16, Value) {
+    match split_cell_id(cell) {
+        Some(position) if !access.can_read(&[position]) => {
+            (403, error_body("permission_denied", "Permission denied"))
+        }
+        Some(position) => {
+            let dependents: Vec<_> = find_direct_dependents(position)
+                .iter()
+                .map(pos_to_cell_id)
+                .collect();
+            (200, json!({"cell": cell, "dependents": dependents}))
+        }
+        None => invalid_cell(cell),
+    }
+}
+
+fn invalid_cell(cell: &str) -> (u16, Value) {
+    (
+        400,
+        error_body("invalid_key", &format!("Invalid cell: {}", cell)),
+    )
+}
+
+// Decode the `%XX` escapes of a path segment, `None` if they are malformed or not UTF-8
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut rest = segment.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let digits = rest
+            .get(..2)
+            .filter(|digits| digits.iter().all(u8::is_ascii_hexdigit))?;
+        bytes.push(u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?);
+        rest = &rest[2..];
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn error_body(code: &str, message: &str) -> Value {
+    json!({"error": {"code": code, "message": message}})
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        _ => "Unprocessable Entity",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::mpsc;
+
+    fn request(method: &str, path: &str, body: &str) -> HttpRequest {
+        HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: String::new(),
+            authorization: None,
+            body: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn head_lines_are_bounded() {
+        let head = read_head(&mut Cursor::new("GET / HTTP/1.1\r\nHost: x\r\n\r\nbody")).unwrap();
+        assert_eq!(head, ["GET / HTTP/1.1", "Host: x"]);
+
+        let long = format!(
+            "GET /{} HTTP/1.1\r\n\r\n",
+            "a".repeat(MAX_LINE_LENGTH as usize)
+        );
+        assert!(read_head(&mut Cursor::new(long)).is_err());
+        let many = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "X: y\r\n".repeat(MAX_HEAD_LINES)
+        );
+        assert!(read_head(&mut Cursor::new(many)).is_err());
+        assert!(read_head(&mut Cursor::new("GET / HTTP/1.1\r\n")).is_err());
+    }
+
+    #[test]
+    fn segments_are_percent_decoded() {
+        assert_eq!(percent_decode("A1").as_deref(), Some("A1"));
+        assert_eq!(
+            percent_decode("Orders%5BTotal%5d").as_deref(),
+            Some("Orders[Total]")
+        );
+        assert_eq!(percent_decode("%2D%2Dforce").as_deref(), Some("--force"));
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(percent_decode("%+1"), None);
+        assert_eq!(percent_decode("%FF"), None);
+    }
+
+    #[test]
+    fn cells_are_checked_before_commands() {
+        // Nothing reaches the engine, the receiver is dropped
+        let (tx, _) = mpsc::channel();
+        let session = Session::anonymous(0);
+        for path in ["/cells/--force", "/cells/%2D%2Dforce", "/cells/A1%20B1"] {
+            let (status, body) = route(&request("PUT", path, "A1 5"), &tx, &session);
+            assert_eq!(status, 400);
+            assert_eq!(body["error"]["code"], "invalid_key");
+        }
+        let (status, _) = route(&request("GET", "/cells/%zz", ""), &tx, &session);
+        assert_eq!(status, 400);
+    }
+
+    #[test]
+    fn large_ranges_are_refused() {
+        let (status, body) = get_range("A1_ZZZZZZ4294967295", &Access::full());
+        assert_eq!(status, 413);
+        assert_eq!(body["error"]["code"], "range_too_large");
+        let (status, _) = get_range("A1_B2", &Access::default());
+        assert_eq!(status, 403);
+    }
+}
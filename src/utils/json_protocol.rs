@@ -0,0 +1,148 @@
+use crate::utils::auth::Session;
+use crate::utils::command::{is_read, parse_command, Command, Response};
+use crate::utils::engine::Transaction;
+use crate::utils::value::CellValue;
+use serde_json::{json, Map, Value};
+use std::sync::mpsc::Sender;
+
+// JSON-lines protocol, negotiated by sending `protocol json` on a connection
+//
+// Every line is a request or a batch of requests, and gets exactly one line back:
+//   {"id": 1, "command": "get A1"}
+//     -> {"id": 1, "ok": true, "cell": "A1", "value": {"type": "int", "value": 5}}
+//   {"id": 2, "command": "set A1 1 +"}
+//     -> {"id": 2, "ok": false, "error": {"code": "invalid_formula", "message": "..."}}
+//   [{"id": 3, "command": "set A1 5"}, {"id": 4, "command": "get A1"}]
+//     -> [{"id": 3, "ok": true}, {"id": 4, "ok": true, ...}]
+// The id is any JSON value chosen by the client and is sent back unchanged
+//
+// Single requests with an id are pipelined: the connection reads on without waiting, and
+// replies are sent as requests complete, possibly out of order
+// Changes reach the spreadsheet in the order they were sent, and a `get` sees every change
+// sent before it on the connection, even those not answered yet
+// Requests without an id and batches are answered in order
+
+// Kinds of errors by the start of their message, the first match wins
+const ERROR_CODES: &[(&str, &str)] = &[
+    ("Error parsing request", "invalid_request"),
+    ("Invalid Key Provided", "invalid_key"),
+    ("Invalid formula", "invalid_formula"),
+    ("Unknown name", "unknown_name"),
+    ("Unsupported Command", "unsupported_command"),
+    ("Cell ", "cycle"),
+    ("Permission denied", "permission_denied"),
+    ("Protected cell", "protected"),
+    ("Not logged in", "unauthenticated"),
+    ("Range too large", "range_too_large"),
+    ("Invalid destination", "invalid_destination"),
+];
+
+// Line confirming that a connection switched to this protocol
+pub fn negotiated() -> String {
+    json!({"protocol": "json"}).to_string()
+}
+
+// Whether a line is a single request with an id, which may be answered out of order
+pub fn is_pipelined(message: &str) -> bool {
+    serde_json::from_str::<Value>(message)
+        .is_ok_and(|request| request.get("id").is_some_and(|id| !id.is_null()))
+}
+
+// Run the request or batch of requests of a line and give the line to reply with
+pub fn handle_message(
+    message: &str,
+    transactions_sender: &Sender<Transaction>,
+    session: &Session,
+) -> String {
+    let reply = match serde_json::from_str::<Value>(message) {
+        Ok(Value::Array(requests)) => Value::Array(
+            requests
+                .iter()
+                .map(|request| handle_request(request, transactions_sender, session))
+                .collect(),
+        ),
+        Ok(request) => handle_request(&request, transactions_sender, session),
+        Err(e) => failure(Value::Null, "invalid_json", &e.to_string()),
+    };
+    reply.to_string()
+}
+
+// Run a pipelined request and give the line to reply with
+// A read first waits for the changes the connection sent before it to be applied
+pub fn handle_pipelined(
+    message: &str,
+    transactions_sender: &Sender<Transaction>,
+    session: &Session,
+) -> String {
+    let read = serde_json::from_str::<Value>(message)
+        .ok()
+        .and_then(|request| request.get("command")?.as_str().map(is_read))
+        .unwrap_or(false);
+    if read {
+        Command::sync(transactions_sender, session);
+    }
+    handle_message(message, transactions_sender, session)
+}
+
+fn handle_request(
+    request: &Value,
+    transactions_sender: &Sender<Transaction>,
+    session: &Session,
+) -> Value {
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let command = match request.get("command").and_then(Value::as_str) {
+        Some(command) => command,
+        None => return failure(id, "invalid_request", "A request needs a `command` string"),
+    };
+
+    match parse_command(command).execute(transactions_sender, session) {
+        None => json!({"id": id, "ok": true}),
+        Some(Response::Value(cell, value)) => {
+            json!({"id": id, "ok": true, "cell": cell, "value": typed_value(&value)})
+        }
+        Some(Response::Error(e)) => {
+            let (code, message) = classify_error(&e);
+            failure(id, code, message)
+        }
+    }
+}
+
+fn failure(id: Value, code: &str, message: &str) -> Value {
+    json!({"id": id, "ok": false, "error": {"code": code, "message": message}})
+}
+
+// Code and message of an error reply, without the `Error: ` in front of the message
+pub fn classify_error(e: &str) -> (&'static str, &str) {
+    let message = e.strip_prefix("Error: ").unwrap_or(e);
+    let code = ERROR_CODES
+        .iter()
+        .find(|(start, _)| message.starts_with(start))
+        .map_or("error", |(_, code)| code);
+    (code, message)
+}
+
+// A value with its type, numbers that JSON cannot hold exactly are sent as text
+pub fn typed_value(value: &CellValue) -> Value {
+    let (kind, content) = match value {
+        CellValue::None => ("none", None),
+        CellValue::Bool(value) => ("bool", Some(json!(value))),
+        CellValue::Int(value) => ("int", Some(json!(value))),
+        CellValue::Float(value) => ("float", Some(json!(value))),
+        CellValue::Decimal(value) => ("decimal", Some(json!(value.to_string()))),
+        CellValue::Date(value) => ("date", Some(json!(value.to_string()))),
+        CellValue::DateTime(value) => ("datetime", Some(json!(value.to_string()))),
+        CellValue::Duration(value) => ("duration", Some(json!(value.to_string()))),
+        CellValue::String(value) => ("string", Some(json!(value))),
+        CellValue::Error(value) => ("error", Some(json!(value))),
+    };
+
+    let mut typed = Map::new();
+    typed.insert(String::from("type"), json!(kind));
+    if let Some(content) = content {
+        typed.insert(String::from("value"), content);
+    }
+    if let CellValue::Duration(duration) = value {
+        typed.insert(String::from("seconds"), json!(duration.0));
+    }
+    Value::Object(typed)
+}
@@ -0,0 +1,53 @@
+use crate::utils::time::DateTime;
+use log::kv::{self, Key, Value, VisitSource};
+use serde_json::{json, Map};
+use std::io::Write;
+
+// How log records are written to standard error
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, clap::ValueEnum)]
+pub enum LogFormat {
+    // env_logger's own lines, with the fields of a record after its message
+    #[default]
+    Text,
+    // One JSON object per record, the fields of a record next to its message
+    Json,
+}
+
+// Set up logging, filtered by `RUST_LOG` as env_logger does
+// Connections are logged at `info`, commands and the requests applied by the engine at `debug`
+pub fn init_logging(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let mut fields = Map::new();
+            fields.insert(String::from("time"), json!(DateTime::now().to_string()));
+            fields.insert(String::from("level"), json!(record.level().as_str()));
+            fields.insert(String::from("target"), json!(record.target()));
+            fields.insert(String::from("message"), json!(record.args().to_string()));
+            let _ = record.key_values().visit(&mut JsonFields(&mut fields));
+            writeln!(buf, "{}", serde_json::Value::Object(fields))
+        });
+    }
+    builder.init();
+}
+
+// Adds the fields of a record to a JSON object, as numbers and booleans where they are
+struct JsonFields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(number) = value.to_u64() {
+            json!(number)
+        } else if let Some(number) = value.to_i64() {
+            json!(number)
+        } else if let Some(number) = value.to_f64() {
+            json!(number)
+        } else if let Some(flag) = value.to_bool() {
+            json!(flag)
+        } else {
+            json!(value.to_string())
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Upper bounds of the buckets of the recomputation time, in seconds
+const SECONDS_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+// Upper bounds of the buckets of the number of cells recomputed by a request
+const CELLS_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 50.0, 100.0, 500.0, 1000.0, 10000.0];
+
+// Counters and histograms served by `GET /metrics` in the Prometheus text format
+lazy_static! {
+    static ref COMMANDS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+    static ref RECALCULATION_SECONDS: Mutex<Histogram> =
+        Mutex::new(Histogram::new(SECONDS_BUCKETS));
+    static ref RECALCULATED_CELLS: Mutex<Histogram> = Mutex::new(Histogram::new(CELLS_BUCKETS));
+}
+
+static QUEUED_TRANSACTIONS: AtomicI64 = AtomicI64::new(0);
+static CYCLES_DETECTED: AtomicU64 = AtomicU64::new(0);
+static OPEN_CONNECTIONS: AtomicI64 = AtomicI64::new(0);
+
+struct Histogram {
+    bounds: &'static [f64],
+    // Observations in each bucket, not cumulative
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, text: &mut String, name: &str, help: &str) {
+        let _ = writeln!(text, "# HELP {} {}", name, help);
+        let _ = writeln!(text, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(text, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let _ = writeln!(text, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(text, "{}_sum {}", name, self.sum);
+        let _ = writeln!(text, "{}_count {}", name, self.count);
+    }
+}
+
+// A connection counted as open until this is dropped
+pub struct OpenConnection;
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn record_command(command: &str) {
+    *COMMANDS
+        .lock()
+        .unwrap()
+        .entry(command.to_string())
+        .or_insert(0) += 1;
+}
+
+// A transaction was created for the engine thread, or taken by it with `false`
+pub fn record_queued(queued: bool) {
+    let change = if queued { 1 } else { -1 };
+    QUEUED_TRANSACTIONS.fetch_add(change, Ordering::Relaxed);
+}
+
+// Time taken and number of cells of the recomputation after a change
+pub fn record_recalculation(elapsed: Duration, cells: usize, cycle: bool) {
+    RECALCULATION_SECONDS
+        .lock()
+        .unwrap()
+        .observe(elapsed.as_secs_f64());
+    RECALCULATED_CELLS.lock().unwrap().observe(cells as f64);
+    if cycle {
+        CYCLES_DETECTED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn open_connection() -> OpenConnection {
+    OPEN_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    OpenConnection
+}
+
+// Every metric in the Prometheus text exposition format
+pub fn render_metrics() -> String {
+    let mut text = String::new();
+
+    text.push_str("# HELP rsheet_commands_total Commands received, by command\n");
+    text.push_str("# TYPE rsheet_commands_total counter\n");
+    for (command, count) in COMMANDS.lock().unwrap().iter() {
+        let _ = writeln!(
+            text,
+            "rsheet_commands_total{{command=\"{}\"}} {}",
+            command, count
+        );
+    }
+
+    let _ = writeln!(
+        text,
+        "# HELP rsheet_transaction_queue_depth Requests waiting for the engine thread\n\
+         # TYPE rsheet_transaction_queue_depth gauge\n\
+         rsheet_transaction_queue_depth {}",
+        QUEUED_TRANSACTIONS.load(Ordering::Relaxed)
+    );
+
+    RECALCULATION_SECONDS.lock().unwrap().render(
+        &mut text,
+        "rsheet_recalculation_seconds",
+        "Time spent recomputing cells after a change",
+    );
+    RECALCULATED_CELLS.lock().unwrap().render(
+        &mut text,
+        "rsheet_recalculated_cells",
+        "Cells recomputed after a change",
+    );
+
+    let _ = writeln!(
+        text,
+        "# HELP rsheet_cycles_detected_total Recomputations that found a circular reference\n\
+         # TYPE rsheet_cycles_detected_total counter\n\
+         rsheet_cycles_detected_total {}",
+        CYCLES_DETECTED.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        text,
+        "# HELP rsheet_open_connections Connections open on every front-end\n\
+         # TYPE rsheet_open_connections gauge\n\
+         rsheet_open_connections {}",
+        OPEN_CONNECTIONS.load(Ordering::Relaxed)
+    );
+    text
+}
@@ -0,0 +1,535 @@
+use crate::utils::decimal::Decimal;
+use crate::utils::formula::{tokenize, Token, TokenKind};
+use crate::utils::time::{is_date_shaped, Duration};
+use crate::utils::value::CellValue;
+use std::fmt::{self, Display, Formatter};
+
+// Parsed formula
+#[derive(Clone, PartialEq, Debug)]
+pub enum Expr {
+    // Number, decimal, string, boolean, duration or `()` literal,
+    // or a date or date-time making up the whole formula
+    Literal(CellValue),
+    // Cell or range reference, defined name or table column
+    Variable(String),
+    // Function call with its arguments
+    Call(String, Vec<Expr>),
+    // Element of a range, `A1_A3[0]`, or row of a matrix, `A1_C3[1]`
+    Index(Box<Expr>, Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    Or,
+}
+
+impl BinaryOp {
+    fn from_symbol(symbol: &str) -> Option<Self> {
+        match symbol {
+            "+" => Some(BinaryOp::Add),
+            "-" => Some(BinaryOp::Subtract),
+            "*" => Some(BinaryOp::Multiply),
+            "/" => Some(BinaryOp::Divide),
+            "%" => Some(BinaryOp::Remainder),
+            "==" => Some(BinaryOp::Equal),
+            "!=" => Some(BinaryOp::NotEqual),
+            "<" => Some(BinaryOp::Less),
+            "<=" => Some(BinaryOp::LessEqual),
+            ">" => Some(BinaryOp::Greater),
+            ">=" => Some(BinaryOp::GreaterEqual),
+            "&&" => Some(BinaryOp::And),
+            "||" => Some(BinaryOp::Or),
+            _ => None,
+        }
+    }
+
+    // Binding strength, operators with a higher precedence are applied first
+    fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::And => 2,
+            BinaryOp::Equal
+            | BinaryOp::NotEqual
+            | BinaryOp::Less
+            | BinaryOp::LessEqual
+            | BinaryOp::Greater
+            | BinaryOp::GreaterEqual => 3,
+            BinaryOp::Add | BinaryOp::Subtract => 4,
+            BinaryOp::Multiply | BinaryOp::Divide | BinaryOp::Remainder => 5,
+        }
+    }
+}
+
+impl Display for BinaryOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Subtract => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Remainder => "%",
+            BinaryOp::Equal => "==",
+            BinaryOp::NotEqual => "!=",
+            BinaryOp::Less => "<",
+            BinaryOp::LessEqual => "<=",
+            BinaryOp::Greater => ">",
+            BinaryOp::GreaterEqual => ">=",
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+impl Expr {
+    // Every variable the formula reads, in order of appearance
+    pub fn variables(&self) -> Vec<&str> {
+        let mut variables = Vec::new();
+        self.collect_variables(&mut variables);
+        variables
+    }
+
+    fn collect_variables<'a>(&'a self, variables: &mut Vec<&'a str>) {
+        match self {
+            Expr::Literal(_) => {}
+            Expr::Variable(name) => variables.push(name),
+            Expr::Call(_, args) => {
+                for arg in args {
+                    arg.collect_variables(variables);
+                }
+            }
+            Expr::Index(base, index) => {
+                base.collect_variables(variables);
+                index.collect_variables(variables);
+            }
+            Expr::Unary(_, operand) => operand.collect_variables(variables),
+            Expr::Binary(_, left, right) => {
+                left.collect_variables(variables);
+                right.collect_variables(variables);
+            }
+        }
+    }
+}
+
+// Formulas are parsed and evaluated recursively, deeper ones would overflow the stack
+// Deepest nesting of parentheses, calls, indexes and unary operators in a formula
+const MAX_NESTING: usize = 100;
+// Deepest formula tree, where each operator of a chain like `1+2+3` is one level deeper
+const MAX_DEPTH: usize = 1000;
+
+// Formula that does not parse, with the offending token (`None` at the end of the formula)
+// and the column it starts at, counting from 1
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ParseError {
+    pub message: String,
+    pub token: Option<String>,
+    pub column: usize,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.token {
+            Some(token) => write!(f, "{} `{}` at column {}", self.message, token, self.column),
+            None => write!(f, "{} at column {}", self.message, self.column),
+        }
+    }
+}
+
+// Parse the text of a formula
+pub fn parse(expr: &str) -> Result<Expr, ParseError> {
+    if let Some(value) = CellValue::parse_literal(expr.trim()) {
+        return Ok(Expr::Literal(value));
+    }
+    // A day or time that does not exist, rather than a subtraction like `2024 - 02 - 30`
+    if is_date_shaped(expr.trim()) {
+        return Err(ParseError {
+            message: String::from("Invalid date"),
+            token: Some(expr.trim().to_string()),
+            column: column(expr, expr.len() - expr.trim_start().len()),
+        });
+    }
+
+    let tokens = tokenize(expr).map_err(|e| ParseError {
+        message: e.message,
+        token: None,
+        column: column(expr, e.offset),
+    })?;
+    let mut parser = Parser {
+        text: expr,
+        tokens,
+        position: 0,
+        nesting: 0,
+        depth: 0,
+    };
+    let result = parser.parse_expression(0)?;
+    match parser.peek() {
+        Some(_) => Err(parser.unexpected()),
+        None => Ok(result),
+    }
+}
+
+// Column of a byte offset, counting characters from 1
+fn column(expr: &str, offset: usize) -> usize {
+    expr[..offset].chars().count() + 1
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    tokens: Vec<Token>,
+    position: usize,
+    // Levels of nesting and of the formula tree above the token being parsed
+    nesting: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn peek_symbol(&self) -> Option<&'static str> {
+        match self.peek()?.kind {
+            TokenKind::Symbol(symbol) => Some(symbol),
+            _ => None,
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), ParseError> {
+        if self.peek_symbol() == Some(symbol) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(self.unexpected())
+        }
+    }
+
+    // Error for the token at the current position
+    fn unexpected(&self) -> ParseError {
+        match self.peek() {
+            Some(token) => ParseError {
+                message: String::from("Unexpected token"),
+                token: Some(self.text[token.span.clone()].to_string()),
+                column: column(self.text, token.span.start),
+            },
+            None => ParseError {
+                message: String::from("Unexpected end of formula"),
+                token: None,
+                column: column(self.text, self.text.len()),
+            },
+        }
+    }
+
+    // Go one level deeper into the formula tree, and into its nesting unless for an operator
+    fn descend(&mut self, nested: bool) -> Result<(), ParseError> {
+        self.depth += 1;
+        self.nesting += nested as usize;
+        let message = if self.nesting > MAX_NESTING {
+            format!("Formula nested more than {} levels deep", MAX_NESTING)
+        } else if self.depth > MAX_DEPTH {
+            format!("Formula more than {} operations deep", MAX_DEPTH)
+        } else {
+            return Ok(());
+        };
+        Err(ParseError {
+            message,
+            ..self.unexpected()
+        })
+    }
+
+    fn ascend(&mut self) {
+        self.depth -= 1;
+        self.nesting -= 1;
+    }
+
+    // Operators of at least `min_precedence`, all binding to the left
+    // Each operator of a chain like `1+2+3` nests the operators before it one level deeper
+    fn parse_expression(&mut self, min_precedence: u8) -> Result<Expr, ParseError> {
+        let depth = self.depth;
+        let mut left = self.parse_unary()?;
+        while let Some(op) = self.peek_symbol().and_then(BinaryOp::from_symbol) {
+            if op.precedence() < min_precedence {
+                break;
+            }
+            self.descend(false)?;
+            self.position += 1;
+            let right = self.parse_expression(op.precedence() + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        self.descend(true)?;
+        let op = match self.peek_symbol() {
+            Some("-") => UnaryOp::Negate,
+            Some("!") => UnaryOp::Not,
+            _ => {
+                let operand = self.parse_postfix();
+                self.ascend();
+                return operand;
+            }
+        };
+        self.position += 1;
+        let operand = self.parse_unary()?;
+        self.ascend();
+        Ok(Expr::Unary(op, Box::new(operand)))
+    }
+
+    fn parse_postfix(&mut self) -> Result<Expr, ParseError> {
+        let mut base = self.parse_primary()?;
+        while self.peek_symbol() == Some("[") {
+            self.position += 1;
+            let index = self.parse_expression(0)?;
+            self.expect("]")?;
+            base = Expr::Index(Box::new(base), Box::new(index));
+        }
+        Ok(base)
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, ParseError> {
+        let error = self.unexpected();
+        let token = match self.next() {
+            Some(token) => token,
+            None => return Err(error),
+        };
+        match token.kind {
+            TokenKind::Text(text) => Ok(Expr::Literal(CellValue::String(text))),
+            TokenKind::Symbol("(") => {
+                if self.peek_symbol() == Some(")") {
+                    self.position += 1;
+                    return Ok(Expr::Literal(CellValue::None));
+                }
+                let inner = self.parse_expression(0)?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            TokenKind::Symbol(_) => Err(error),
+            TokenKind::Word => {
+                let word = &self.text[token.span.clone()];
+                if self.peek_symbol() == Some("(") {
+                    self.position += 1;
+                    let args = self.parse_arguments()?;
+                    return Ok(Expr::Call(word.to_string(), args));
+                }
+                match word {
+                    "true" | "TRUE" => Ok(Expr::Literal(CellValue::Bool(true))),
+                    "false" | "FALSE" => Ok(Expr::Literal(CellValue::Bool(false))),
+                    _ if word.bytes().all(|byte| byte.is_ascii_digit()) => {
+                        match word.parse::<i64>() {
+                            Ok(number) => Ok(Expr::Literal(CellValue::Int(number))),
+                            Err(_) => Err(ParseError {
+                                message: String::from("Number too large"),
+                                ..error
+                            }),
+                        }
+                    }
+                    _ if word.starts_with(|ch: char| ch.is_ascii_digit())
+                        && !word.contains('_') =>
+                    {
+                        // Decimals like `19.99`, floats with an exponent like `1.5e3`
+                        // and durations like `1h30m`
+                        if let Some(number) = Decimal::parse(word) {
+                            return Ok(Expr::Literal(CellValue::Decimal(number)));
+                        }
+                        match (word.parse::<f64>(), Duration::parse(word)) {
+                            (Ok(number), _) if number.is_finite() => {
+                                Ok(Expr::Literal(CellValue::Float(number)))
+                            }
+                            (_, Some(duration)) => Ok(Expr::Literal(CellValue::Duration(duration))),
+                            _ => Err(ParseError {
+                                message: String::from("Invalid number"),
+                                ..error
+                            }),
+                        }
+                    }
+                    _ => Ok(Expr::Variable(word.to_string())),
+                }
+            }
+        }
+    }
+
+    // Arguments of a call, after its opening parenthesis
+    fn parse_arguments(&mut self) -> Result<Vec<Expr>, ParseError> {
+        let mut args = Vec::new();
+        if self.peek_symbol() == Some(")") {
+            self.position += 1;
+            return Ok(args);
+        }
+        loop {
+            args.push(self.parse_expression(0)?);
+            match self.peek_symbol() {
+                Some(",") => self.position += 1,
+                Some(")") => {
+                    self.position += 1;
+                    return Ok(args);
+                }
+                _ => return Err(self.unexpected()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(value: i64) -> Box<Expr> {
+        Box::new(Expr::Literal(CellValue::Int(value)))
+    }
+
+    fn variable(name: &str) -> Box<Expr> {
+        Box::new(Expr::Variable(name.to_string()))
+    }
+
+    #[test]
+    fn precedence_and_left_association() {
+        assert_eq!(
+            parse("1 + 2 * 3"),
+            Ok(Expr::Binary(
+                BinaryOp::Add,
+                int(1),
+                Box::new(Expr::Binary(BinaryOp::Multiply, int(2), int(3)))
+            ))
+        );
+        assert_eq!(
+            parse("1 - 2 - 3"),
+            Ok(Expr::Binary(
+                BinaryOp::Subtract,
+                Box::new(Expr::Binary(BinaryOp::Subtract, int(1), int(2))),
+                int(3)
+            ))
+        );
+        assert_eq!(
+            parse("(1 + 2) * 3"),
+            Ok(Expr::Binary(
+                BinaryOp::Multiply,
+                Box::new(Expr::Binary(BinaryOp::Add, int(1), int(2))),
+                int(3)
+            ))
+        );
+        assert_eq!(
+            parse("A1 < 2 || !B1"),
+            Ok(Expr::Binary(
+                BinaryOp::Or,
+                Box::new(Expr::Binary(BinaryOp::Less, variable("A1"), int(2))),
+                Box::new(Expr::Unary(UnaryOp::Not, variable("B1")))
+            ))
+        );
+    }
+
+    #[test]
+    fn calls_indexes_and_literals() {
+        assert_eq!(
+            parse("SUM(A1_A3, -2)"),
+            Ok(Expr::Call(
+                String::from("SUM"),
+                vec![
+                    Expr::Variable(String::from("A1_A3")),
+                    Expr::Unary(UnaryOp::Negate, int(2))
+                ]
+            ))
+        );
+        assert_eq!(
+            parse("A1_C3[1][0]"),
+            Ok(Expr::Index(
+                Box::new(Expr::Index(variable("A1_C3"), int(1))),
+                int(0)
+            ))
+        );
+        assert_eq!(parse("()"), Ok(Expr::Literal(CellValue::None)));
+        assert_eq!(parse("TRUE"), Ok(Expr::Literal(CellValue::Bool(true))));
+        assert_eq!(parse("1.5e3"), Ok(Expr::Literal(CellValue::Float(1500.0))));
+        assert_eq!(
+            parse("\"a b\""),
+            Ok(Expr::Literal(CellValue::String(String::from("a b"))))
+        );
+        assert_eq!(
+            parse("Orders.Amount * 2").unwrap().variables(),
+            vec!["Orders.Amount"]
+        );
+    }
+
+    #[test]
+    fn errors_point_at_token() {
+        let error = parse("1 + * 2").unwrap_err();
+        assert_eq!(error.message, "Unexpected token");
+        assert_eq!(error.token.as_deref(), Some("*"));
+        assert_eq!(error.column, 5);
+
+        let error = parse("SUM(1, 2").unwrap_err();
+        assert_eq!(error.message, "Unexpected end of formula");
+        assert_eq!(error.token, None);
+        assert_eq!(error.column, 9);
+
+        assert_eq!(
+            parse("99999999999999999999").unwrap_err().message,
+            "Number too large"
+        );
+    }
+
+    #[test]
+    fn date_literals() {
+        assert!(matches!(
+            parse("2024-02-29"),
+            Ok(Expr::Literal(CellValue::Date(_)))
+        ));
+        let error = parse(" 2024-02-30").unwrap_err();
+        assert_eq!(error.message, "Invalid date");
+        assert_eq!(error.column, 2);
+        assert!(parse("2024-01-01T25:00").is_err());
+    }
+
+    #[test]
+    fn nesting_limit() {
+        let nested = |levels: usize| format!("{}1{}", "(".repeat(levels), ")".repeat(levels));
+        assert!(parse(&nested(MAX_NESTING - 1)).is_ok());
+        let error = parse(&nested(2000)).unwrap_err();
+        assert_eq!(
+            error.message,
+            format!("Formula nested more than {} levels deep", MAX_NESTING)
+        );
+
+        let calls = |levels: usize| format!("{}1{}", "SUM(".repeat(levels), ")".repeat(levels));
+        assert!(parse(&calls(MAX_NESTING - 1)).is_ok());
+        assert!(parse(&calls(MAX_NESTING + 1)).is_err());
+        assert!(parse(&"-".repeat(2000)).is_err());
+    }
+
+    #[test]
+    fn depth_limit() {
+        let chain = |terms: usize| vec!["1"; terms].join("+");
+        assert!(parse(&chain(MAX_DEPTH - 1)).is_ok());
+        let error = parse(&chain(100000)).unwrap_err();
+        assert_eq!(
+            error.message,
+            format!("Formula more than {} operations deep", MAX_DEPTH)
+        );
+    }
+}
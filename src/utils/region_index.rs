@@ -0,0 +1,393 @@
+use crate::utils::database::Region;
+
+// Maximum number of entries in a node of the tree before it is split
+const MAX_ENTRIES: usize = 8;
+
+// Closed rectangle of cells, open sides of a region extend to the edge of the sheet
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Rect {
+    min: (u32, u32),
+    max: (u32, u32),
+}
+
+impl Rect {
+    fn from_region(region: &Region) -> Self {
+        let (min, max) = region.bounds();
+        Rect { min, max }
+    }
+
+    fn point(position: &(u32, u32)) -> Self {
+        Rect {
+            min: *position,
+            max: *position,
+        }
+    }
+
+    fn union(&self, other: &Rect) -> Rect {
+        Rect {
+            min: (self.min.0.min(other.min.0), self.min.1.min(other.min.1)),
+            max: (self.max.0.max(other.max.0), self.max.1.max(other.max.1)),
+        }
+    }
+
+    fn area(&self) -> u128 {
+        (self.max.0 - self.min.0) as u128 * (self.max.1 - self.min.1) as u128
+            + (self.max.0 - self.min.0) as u128
+            + (self.max.1 - self.min.1) as u128
+    }
+
+    fn enlargement(&self, other: &Rect) -> u128 {
+        self.union(other).area() - self.area()
+    }
+
+    fn contains(&self, other: &Rect) -> bool {
+        self.min.0 <= other.min.0
+            && self.min.1 <= other.min.1
+            && self.max.0 >= other.max.0
+            && self.max.1 >= other.max.1
+    }
+}
+
+// Entries of a node, each with the rectangle covering it
+type Entries<X> = Vec<(Rect, X)>;
+
+enum Node<T> {
+    Leaf(Entries<T>),
+    Inner(Entries<Node<T>>),
+}
+
+impl<T> Node<T> {
+    fn bounding_rect(&self) -> Option<Rect> {
+        match self {
+            Node::Leaf(entries) => entries
+                .iter()
+                .map(|entry| entry.0)
+                .reduce(|a, b| a.union(&b)),
+            Node::Inner(children) => children
+                .iter()
+                .map(|child| child.0)
+                .reduce(|a, b| a.union(&b)),
+        }
+    }
+}
+
+// Spatial index of rectangular regions (an R-tree), answering which regions contain a cell
+// Every region carries a value, here the formula cell that depends on it
+pub struct RegionIndex<T> {
+    root: Node<T>,
+}
+
+impl<T: Clone + PartialEq> RegionIndex<T> {
+    pub fn new() -> Self {
+        RegionIndex {
+            root: Node::Leaf(Vec::new()),
+        }
+    }
+
+    pub fn insert(&mut self, region: &Region, value: T) {
+        if let Some(sibling) = insert_into(&mut self.root, Rect::from_region(region), value) {
+            // The root was split, grow the tree by one level
+            let old_root = std::mem::replace(&mut self.root, Node::Inner(Vec::new()));
+            if let (Some(old_rect), Node::Inner(children)) =
+                (old_root.bounding_rect(), &mut self.root)
+            {
+                children.push((old_rect, old_root));
+                children.push(sibling);
+            }
+        }
+    }
+
+    // Remove one entry of the region with the given value
+    // Returns whether such an entry was found
+    pub fn remove(&mut self, region: &Region, value: &T) -> bool {
+        remove_from(&mut self.root, &Rect::from_region(region), value)
+    }
+
+    // Values of all regions containing the cell
+    pub fn query(&self, position: &(u32, u32)) -> Vec<T> {
+        let mut result = Vec::new();
+        query_node(&self.root, &Rect::point(position), &mut result);
+        result
+    }
+}
+
+// Insert an entry below a node
+// Returns the new sibling of the node if it had to be split
+fn insert_into<T>(node: &mut Node<T>, rect: Rect, value: T) -> Option<(Rect, Node<T>)> {
+    match node {
+        Node::Leaf(entries) => {
+            entries.push((rect, value));
+            if entries.len() > MAX_ENTRIES {
+                let (kept, moved) = split(std::mem::take(entries));
+                *entries = kept;
+                let moved = Node::Leaf(moved);
+                return moved.bounding_rect().map(|rect| (rect, moved));
+            }
+            None
+        }
+        Node::Inner(children) if children.is_empty() => {
+            *node = Node::Leaf(vec![(rect, value)]);
+            None
+        }
+        Node::Inner(children) => {
+            // Descend into the child whose rectangle grows the least
+            let index = (0..children.len())
+                .min_by_key(|&i| (children[i].0.enlargement(&rect), children[i].0.area()))?;
+            children[index].0 = children[index].0.union(&rect);
+
+            if let Some(sibling) = insert_into(&mut children[index].1, rect, value) {
+                if let Some(rect) = children[index].1.bounding_rect() {
+                    children[index].0 = rect;
+                }
+                children.push(sibling);
+                if children.len() > MAX_ENTRIES {
+                    let (kept, moved) = split(std::mem::take(children));
+                    *children = kept;
+                    let moved = Node::Inner(moved);
+                    return moved.bounding_rect().map(|rect| (rect, moved));
+                }
+            }
+            None
+        }
+    }
+}
+
+// Quadratic split of an overflowing node into two groups of entries
+fn split<X>(mut entries: Entries<X>) -> (Entries<X>, Entries<X>) {
+    // Pick the two entries that would waste the most area if grouped together
+    let mut seeds = (0, 1);
+    let mut worst = 0;
+    for i in 0..entries.len() {
+        for j in i + 1..entries.len() {
+            let waste = entries[i].0.union(&entries[j].0).area()
+                - entries[i].0.area().min(entries[j].0.area());
+            if waste >= worst {
+                worst = waste;
+                seeds = (i, j);
+            }
+        }
+    }
+
+    let second = entries.swap_remove(seeds.1);
+    let first = entries.swap_remove(seeds.0);
+    let (mut first_rect, mut second_rect) = (first.0, second.0);
+    let (mut first_group, mut second_group) = (vec![first], vec![second]);
+
+    // Assign every other entry to the group that grows the least
+    let min_entries = MAX_ENTRIES / 2;
+    while let Some(entry) = entries.pop() {
+        let remaining = entries.len() + 1;
+        let to_first = if first_group.len() + remaining <= min_entries {
+            true
+        } else if second_group.len() + remaining <= min_entries {
+            false
+        } else {
+            first_rect.enlargement(&entry.0) <= second_rect.enlargement(&entry.0)
+        };
+
+        if to_first {
+            first_rect = first_rect.union(&entry.0);
+            first_group.push(entry);
+        } else {
+            second_rect = second_rect.union(&entry.0);
+            second_group.push(entry);
+        }
+    }
+    (first_group, second_group)
+}
+
+// Remove an entry below a node, tightening the rectangles on the way back up
+// Nodes left empty are dropped, underfull nodes are kept as they are
+fn remove_from<T: PartialEq>(node: &mut Node<T>, rect: &Rect, value: &T) -> bool {
+    match node {
+        Node::Leaf(entries) => {
+            match entries
+                .iter()
+                .position(|entry| entry.0 == *rect && entry.1 == *value)
+            {
+                Some(index) => {
+                    entries.swap_remove(index);
+                    true
+                }
+                None => false,
+            }
+        }
+        Node::Inner(children) => {
+            for index in 0..children.len() {
+                if !children[index].0.contains(rect) {
+                    continue;
+                }
+                if remove_from(&mut children[index].1, rect, value) {
+                    match children[index].1.bounding_rect() {
+                        Some(bounding_rect) => children[index].0 = bounding_rect,
+                        None => {
+                            children.swap_remove(index);
+                        }
+                    }
+                    return true;
+                }
+            }
+            false
+        }
+    }
+}
+
+fn query_node<T: Clone>(node: &Node<T>, point: &Rect, result: &mut Vec<T>) {
+    match node {
+        Node::Leaf(entries) => result.extend(
+            entries
+                .iter()
+                .filter(|entry| entry.0.contains(point))
+                .map(|entry| entry.1.clone()),
+        ),
+        Node::Inner(children) => {
+            for (rect, child) in children {
+                if rect.contains(point) {
+                    query_node(child, point, result);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::database::reference_region;
+
+    fn region(variable: &str) -> Region {
+        reference_region(variable).unwrap()
+    }
+
+    // Regions of different sizes spread over the sheet, enough to split nodes several times
+    fn regions() -> Vec<Region> {
+        (0..200)
+            .map(|i| Region {
+                left: Some(i % 13),
+                top: Some(i % 17 + 1),
+                right: Some(i % 13 + i % 5),
+                bottom: Some(i % 17 + 1 + i % 7),
+            })
+            .collect()
+    }
+
+    fn sorted_query(index: &RegionIndex<u32>, position: &(u32, u32)) -> Vec<u32> {
+        let mut values = index.query(position);
+        values.sort();
+        values
+    }
+
+    // Values of the regions containing a cell, found by checking every region
+    fn expected(regions: &[(Region, u32)], position: &(u32, u32)) -> Vec<u32> {
+        let mut values: Vec<_> = regions
+            .iter()
+            .filter(|(region, _)| region.contains(position))
+            .map(|(_, value)| *value)
+            .collect();
+        values.sort();
+        values
+    }
+
+    fn cells() -> impl Iterator<Item = (u32, u32)> {
+        (0..20).flat_map(|col| (0..26).map(move |row| (col, row)))
+    }
+
+    #[test]
+    fn query_finds_regions_containing_cell() {
+        let mut index = RegionIndex::new();
+        index.insert(&region("A1_B2"), 1);
+        index.insert(&region("B2_C3"), 2);
+        index.insert(&region("D4"), 3);
+
+        assert_eq!(sorted_query(&index, &(1, 2)), vec![1, 2]);
+        assert_eq!(sorted_query(&index, &(0, 1)), vec![1]);
+        assert_eq!(sorted_query(&index, &(3, 4)), vec![3]);
+        assert!(index.query(&(4, 4)).is_empty());
+    }
+
+    #[test]
+    fn query_open_region() {
+        let mut index = RegionIndex::new();
+        index.insert(&region("B_B"), 1);
+        index.insert(&region("3_4"), 2);
+
+        assert_eq!(sorted_query(&index, &(1, 1000)), vec![1]);
+        assert_eq!(sorted_query(&index, &(1, 3)), vec![1, 2]);
+        assert_eq!(sorted_query(&index, &(700, 4)), vec![2]);
+        assert!(index.query(&(0, 5)).is_empty());
+    }
+
+    #[test]
+    fn insert_past_max_entries() {
+        let mut index = RegionIndex::new();
+        let regions: Vec<_> = regions().into_iter().zip(0..).collect();
+        for (region, value) in regions.iter() {
+            index.insert(region, *value);
+        }
+
+        assert!(matches!(index.root, Node::Inner(_)));
+        for position in cells() {
+            assert_eq!(
+                sorted_query(&index, &position),
+                expected(&regions, &position)
+            );
+        }
+    }
+
+    #[test]
+    fn same_region_with_several_values() {
+        let mut index = RegionIndex::new();
+        for value in 0..(MAX_ENTRIES as u32 * 3) {
+            index.insert(&region("A1_C3"), value);
+        }
+
+        let values: Vec<_> = (0..(MAX_ENTRIES as u32 * 3)).collect();
+        assert_eq!(sorted_query(&index, &(1, 2)), values);
+        assert!(index.remove(&region("A1_C3"), &5));
+        assert!(!sorted_query(&index, &(1, 2)).contains(&5));
+    }
+
+    #[test]
+    fn remove_entries() {
+        let mut index = RegionIndex::new();
+        let mut regions: Vec<_> = regions().into_iter().zip(0..).collect();
+        for (region, value) in regions.iter() {
+            index.insert(region, *value);
+        }
+
+        // Removing needs the region the value was inserted with
+        let (first_region, first_value) = regions[0];
+        assert!(!index.remove(&region("Z99"), &first_value));
+        assert!(!index.remove(&first_region, &1000));
+
+        let removed: Vec<_> = regions.drain(..100).collect();
+        for (region, value) in removed.iter() {
+            assert!(index.remove(region, value));
+            assert!(!index.remove(region, value));
+        }
+        for position in cells() {
+            assert_eq!(
+                sorted_query(&index, &position),
+                expected(&regions, &position)
+            );
+        }
+    }
+
+    #[test]
+    fn remove_everything_then_insert() {
+        let mut index = RegionIndex::new();
+        let regions: Vec<_> = regions().into_iter().zip(0..).collect();
+        for (region, value) in regions.iter() {
+            index.insert(region, *value);
+        }
+        for (region, value) in regions.iter() {
+            assert!(index.remove(region, value));
+        }
+        for position in cells() {
+            assert!(index.query(&position).is_empty());
+        }
+
+        index.insert(&region("A1_B2"), 7);
+        assert_eq!(index.query(&(0, 2)), vec![7]);
+    }
+}
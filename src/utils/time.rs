@@ -0,0 +1,286 @@
+use std::fmt::{self, Display, Formatter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+// Units of a duration, largest first, as written in literals like `1h30m`
+const DURATION_UNITS: &[(char, i64)] =
+    &[('d', SECONDS_PER_DAY), ('h', 60 * 60), ('m', 60), ('s', 1)];
+
+// Calendar date, stored as the number of days since 1970-01-01
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Date(pub i64);
+
+// Date and time of day in UTC, stored as the number of seconds since 1970-01-01T00:00:00
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct DateTime(pub i64);
+
+// Length of time in seconds, possibly negative
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Duration(pub i64);
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// Year, month and day of a number of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Parse a fixed number of ASCII digits
+fn digits(text: &str) -> Option<i64> {
+    if text.is_empty() || !text.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok()
+}
+
+impl Date {
+    // Date of a year, month and day, `None` if no such day exists
+    pub fn from_ymd(year: i64, month: u32, day: u32) -> Option<Self> {
+        if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+            return None;
+        }
+        Some(Date(days_from_civil(year, month, day)))
+    }
+
+    // Parse `YYYY-MM-DD`
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut parts = text.split('-');
+        let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+        if parts.next().is_some() || year.len() != 4 || month.len() != 2 || day.len() != 2 {
+            return None;
+        }
+        Date::from_ymd(digits(year)?, digits(month)? as u32, digits(day)? as u32)
+    }
+
+    pub fn year_month_day(&self) -> (i64, u32, u32) {
+        civil_from_days(self.0)
+    }
+
+    pub fn at_midnight(&self) -> DateTime {
+        DateTime(self.0 * SECONDS_PER_DAY)
+    }
+}
+
+// Whether text is laid out as a date or date-time literal, whether or not that day and time exist
+pub fn is_date_shaped(text: &str) -> bool {
+    let text = text.strip_suffix('Z').unwrap_or(text);
+    let layout: String = text
+        .chars()
+        .map(|ch| if ch.is_ascii_digit() { '9' } else { ch })
+        .collect();
+    let (date, time) = match layout.split_once(['T', ' ']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (layout.as_str(), None),
+    };
+    date == "9999-99-99" && time.is_none_or(|time| time == "99:99" || time == "99:99:99")
+}
+
+impl Display for Date {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let (year, month, day) = self.year_month_day();
+        write!(f, "{:04}-{:02}-{:02}", year, month, day)
+    }
+}
+
+impl DateTime {
+    // Parse `YYYY-MM-DDTHH:MM[:SS][Z]`, with a space also accepted between date and time
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.strip_suffix('Z').unwrap_or(text);
+        let (date, time) = text.split_once(['T', ' '])?;
+        let date = Date::parse(date)?;
+
+        let mut parts = time.split(':');
+        let (hours, minutes, seconds) = (parts.next()?, parts.next()?, parts.next());
+        if parts.next().is_some() || hours.len() != 2 || minutes.len() != 2 {
+            return None;
+        }
+        let (hours, minutes) = (digits(hours)?, digits(minutes)?);
+        let seconds = match seconds {
+            Some(seconds) if seconds.len() == 2 => digits(seconds)?,
+            Some(_) => return None,
+            None => 0,
+        };
+        if hours > 23 || minutes > 59 || seconds > 59 {
+            return None;
+        }
+        Some(DateTime(
+            date.at_midnight().0 + hours * 3600 + minutes * 60 + seconds,
+        ))
+    }
+
+    // The current time in UTC, to the second
+    pub fn now() -> Self {
+        let elapsed = SystemTime::now().duration_since(UNIX_EPOCH);
+        DateTime(elapsed.map_or(0, |elapsed| elapsed.as_secs() as i64))
+    }
+
+    pub fn date(&self) -> Date {
+        Date(self.0.div_euclid(SECONDS_PER_DAY))
+    }
+
+    // Hours, minutes and seconds since midnight
+    pub fn time(&self) -> (i64, i64, i64) {
+        let seconds = self.0.rem_euclid(SECONDS_PER_DAY);
+        (seconds / 3600, seconds / 60 % 60, seconds % 60)
+    }
+}
+
+impl Display for DateTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let (hours, minutes, seconds) = self.time();
+        write!(
+            f,
+            "{}T{:02}:{:02}:{:02}Z",
+            self.date(),
+            hours,
+            minutes,
+            seconds
+        )
+    }
+}
+
+impl Duration {
+    // Parse a duration like `90s`, `1h30m` or `2d 4h`, optionally negative
+    pub fn parse(text: &str) -> Option<Self> {
+        let (sign, text) = match text.strip_prefix('-') {
+            Some(text) => (-1, text),
+            None => (1, text),
+        };
+        let mut seconds: i64 = 0;
+        let mut number = String::new();
+        let mut has_part = false;
+        for ch in text.chars() {
+            if ch.is_ascii_digit() {
+                number.push(ch);
+            } else if ch == ' ' && number.is_empty() {
+                continue;
+            } else {
+                let (_, unit) = DURATION_UNITS.iter().find(|(name, _)| *name == ch)?;
+                let count: i64 = number.parse().ok()?;
+                seconds = seconds.checked_add(count.checked_mul(*unit)?)?;
+                number.clear();
+                has_part = true;
+            }
+        }
+        if !number.is_empty() || !has_part {
+            return None;
+        }
+        Some(Duration(sign * seconds))
+    }
+}
+
+impl Display for Duration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "0s");
+        }
+        if self.0 < 0 {
+            write!(f, "-")?;
+        }
+        let mut rest = self.0.unsigned_abs();
+        for (name, unit) in DURATION_UNITS {
+            let unit = *unit as u64;
+            if rest >= unit {
+                write!(f, "{}{}", rest / unit, name)?;
+                rest %= unit;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_dates() {
+        assert_eq!(Date::parse("1970-01-01"), Some(Date(0)));
+        assert_eq!(Date::parse("2024-02-29"), Date::from_ymd(2024, 2, 29));
+        assert_eq!(
+            Date::parse("2000-02-29").map(|date| date.to_string()),
+            Some(String::from("2000-02-29"))
+        );
+        assert_eq!(Date::parse("2024-02-30"), None);
+        assert_eq!(Date::parse("2023-02-29"), None);
+        assert_eq!(Date::parse("1900-02-29"), None);
+        assert_eq!(Date::parse("2024-13-01"), None);
+        assert_eq!(Date::parse("2024-1-01"), None);
+    }
+
+    #[test]
+    fn civil_days_round_trip() {
+        for days in [-800_000, -1, 0, 59, 11_016, 19_782, 2_000_000] {
+            let (year, month, day) = Date(days).year_month_day();
+            assert_eq!(Date::from_ymd(year, month, day), Some(Date(days)));
+        }
+        assert_eq!(Date(19_782).to_string(), "2024-02-29");
+    }
+
+    #[test]
+    fn parse_date_times() {
+        let moment = DateTime::parse("2024-03-01T09:30:15Z").unwrap();
+        assert_eq!(moment.date(), Date::parse("2024-03-01").unwrap());
+        assert_eq!(moment.time(), (9, 30, 15));
+        assert_eq!(moment.to_string(), "2024-03-01T09:30:15Z");
+        assert_eq!(
+            DateTime::parse("2024-03-01 09:30"),
+            DateTime::parse("2024-03-01T09:30:00")
+        );
+        assert_eq!(DateTime::parse("2024-03-01T24:00"), None);
+        assert_eq!(DateTime::parse("2024-02-30T10:00"), None);
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(Duration::parse("1h30m"), Some(Duration(5400)));
+        assert_eq!(Duration::parse("2d 4h"), Some(Duration(187_200)));
+        assert_eq!(Duration::parse("-90s"), Some(Duration(-90)));
+        assert_eq!(Duration::parse("90"), None);
+        assert_eq!(Duration::parse("1x"), None);
+    }
+
+    #[test]
+    fn date_shapes() {
+        assert!(is_date_shaped("2024-02-30"));
+        assert!(is_date_shaped("2024-02-30T25:00"));
+        assert!(is_date_shaped("2024-02-30 10:00:61Z"));
+        assert!(!is_date_shaped("2024-2-30"));
+        assert!(!is_date_shaped("2024-02-30 + 1"));
+        assert!(!is_date_shaped("A1-B1-C1"));
+    }
+}
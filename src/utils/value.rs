@@ -0,0 +1,383 @@
+use crate::utils::decimal::{self, Decimal};
+use crate::utils::parser::BinaryOp;
+use crate::utils::time;
+use std::cmp::Ordering;
+use std::fmt::{self, Display, Formatter};
+
+// Value of a cell
+// Besides the values `rsheet_lib` can reply with, cells hold booleans, floats, decimals,
+// dates, date-times and durations, which are formatted as text in replies
+#[derive(Clone, PartialEq, Debug, Default)]
+pub enum CellValue {
+    #[default]
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    // Fixed-point number written with a decimal point, like `19.99`
+    Decimal(Decimal),
+    Date(time::Date),
+    DateTime(time::DateTime),
+    Duration(time::Duration),
+    String(String),
+    Error(String),
+}
+
+// Inputs of a calculation: a single value, or a range of values
+// A range within a single column or row is a vector, any other range a matrix made of rows
+#[derive(Clone, PartialEq, Debug)]
+pub enum CellArgument {
+    Value(CellValue),
+    Vector(Vec<CellValue>),
+    Matrix(Vec<Vec<CellValue>>),
+}
+
+impl CellValue {
+    // Parse an expression that is a literal of a type formulas have no syntax for,
+    // a date like `2024-03-01`, a date-time like `2024-03-01T09:30:00` or a duration like `1h 30m`
+    pub fn parse_literal(text: &str) -> Option<Self> {
+        time::Date::parse(text)
+            .map(CellValue::Date)
+            .or_else(|| time::DateTime::parse(text).map(CellValue::DateTime))
+            .or_else(|| time::Duration::parse(text).map(CellValue::Duration))
+    }
+
+    // The value as text, as `CONCAT` and adding to a string use it
+    pub fn to_text(&self) -> String {
+        match self {
+            CellValue::None => String::new(),
+            CellValue::String(text) | CellValue::Error(text) => text.clone(),
+            CellValue::Bool(value) => value.to_string(),
+            CellValue::Int(value) => value.to_string(),
+            // Whole floats keep a decimal point so they are told apart from integers
+            CellValue::Float(value) if value.fract() == 0.0 && value.is_finite() => {
+                format!("{:.1}", value)
+            }
+            CellValue::Float(value) => value.to_string(),
+            CellValue::Decimal(value) => value.to_string(),
+            CellValue::Date(value) => value.to_string(),
+            CellValue::DateTime(value) => value.to_string(),
+            CellValue::Duration(value) => value.to_string(),
+        }
+    }
+
+    // The value as sent in a reply, types `rsheet_lib` does not know are sent as their text
+    pub fn to_reply_value(&self) -> rsheet_lib::cell_value::CellValue {
+        match self {
+            CellValue::None => rsheet_lib::cell_value::CellValue::None,
+            CellValue::Int(value) => rsheet_lib::cell_value::CellValue::Int(*value),
+            CellValue::Error(e) => rsheet_lib::cell_value::CellValue::Error(e.clone()),
+            value => rsheet_lib::cell_value::CellValue::String(value.to_text()),
+        }
+    }
+
+    // Numbers as floats, for arithmetic mixing integers and floats
+    pub fn to_float(&self) -> Option<f64> {
+        match self {
+            CellValue::Int(value) => Some(*value as f64),
+            CellValue::Float(value) => Some(*value),
+            CellValue::Decimal(value) => Some(value.to_f64()),
+            _ => None,
+        }
+    }
+
+    // Integers and decimals as decimals, for exact arithmetic mixing the two
+    pub fn to_decimal(&self) -> Option<Decimal> {
+        match self {
+            CellValue::Int(value) => Some(Decimal::from_int(*value)),
+            CellValue::Decimal(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn is_number(&self) -> bool {
+        matches!(
+            self,
+            CellValue::Int(_) | CellValue::Float(_) | CellValue::Decimal(_)
+        )
+    }
+
+    // Numbers, dates, date-times and durations, the values `MIN` and `MAX` compare
+    pub fn is_orderable(&self) -> bool {
+        matches!(
+            self,
+            CellValue::Int(_)
+                | CellValue::Float(_)
+                | CellValue::Decimal(_)
+                | CellValue::Date(_)
+                | CellValue::DateTime(_)
+                | CellValue::Duration(_)
+        )
+    }
+}
+
+impl Display for CellValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CellValue::None => write!(f, "None"),
+            CellValue::String(text) => write!(f, "\"{}\"", text),
+            CellValue::Error(e) => write!(f, "Error: \"{}\"", e),
+            value => write!(f, "{}", value.to_text()),
+        }
+    }
+}
+
+// Order of two values of compatible types, `None` if they cannot be compared
+// Numbers of every kind compare with each other, and dates with date-times at midnight
+pub fn compare(a: &CellValue, b: &CellValue) -> Option<Ordering> {
+    match (a, b) {
+        (CellValue::Int(a), CellValue::Int(b)) => Some(a.cmp(b)),
+        (CellValue::String(a), CellValue::String(b)) => Some(a.cmp(b)),
+        (CellValue::Bool(a), CellValue::Bool(b)) => Some(a.cmp(b)),
+        (CellValue::Date(a), CellValue::Date(b)) => Some(a.cmp(b)),
+        (CellValue::DateTime(a), CellValue::DateTime(b)) => Some(a.cmp(b)),
+        (CellValue::Date(a), CellValue::DateTime(b)) => Some(a.at_midnight().cmp(b)),
+        (CellValue::DateTime(a), CellValue::Date(b)) => Some(a.cmp(&b.at_midnight())),
+        (CellValue::Duration(a), CellValue::Duration(b)) => Some(a.cmp(b)),
+        (CellValue::Int(_) | CellValue::Decimal(_), CellValue::Int(_) | CellValue::Decimal(_)) => {
+            Some(a.to_decimal()?.cmp(&b.to_decimal()?))
+        }
+        (a, b) => a.to_float()?.partial_cmp(&b.to_float()?),
+    }
+}
+
+fn overflow() -> CellValue {
+    CellValue::Error(String::from("Error: Arithmetic overflow"))
+}
+
+fn checked(result: Option<i64>, wrap: fn(i64) -> CellValue) -> CellValue {
+    result.map(wrap).unwrap_or_else(overflow)
+}
+
+// Apply an arithmetic operator to two values
+// Integers stay integers, mixing in a decimal gives an exact decimal and mixing in a float
+// gives a float, adding anything to text joins them,
+// and dates, date-times and durations combine the way calendars do:
+// a date plus days is a date, a date-time minus a date-time is a duration, and so on
+pub fn arithmetic(op: BinaryOp, left: &CellValue, right: &CellValue) -> CellValue {
+    use CellValue::{Date, DateTime, Decimal, Duration, Float, Int};
+
+    let is_division = matches!(op, BinaryOp::Divide | BinaryOp::Remainder);
+    if is_division && matches!(right, Int(0) | Duration(time::Duration(0))) {
+        return CellValue::Error(String::from("Error: Division by zero"));
+    }
+
+    match (op, left, right) {
+        (BinaryOp::Add, CellValue::String(_), value)
+        | (BinaryOp::Add, value, CellValue::String(_))
+            if !matches!(value, CellValue::None | CellValue::Error(_)) =>
+        {
+            CellValue::String(format!("{}{}", left.to_text(), right.to_text()))
+        }
+        (op, Int(a), Int(b)) => {
+            let result = match op {
+                BinaryOp::Add => a.checked_add(*b),
+                BinaryOp::Subtract => a.checked_sub(*b),
+                BinaryOp::Multiply => a.checked_mul(*b),
+                BinaryOp::Divide => a.checked_div(*b),
+                BinaryOp::Remainder => a.checked_rem(*b),
+                _ => return unsupported(op, left, right),
+            };
+            checked(result, Int)
+        }
+        (op, Int(_) | Decimal(_), Int(_) | Decimal(_)) => {
+            let (a, b) = (
+                left.to_decimal().unwrap_or(decimal::Decimal::from_int(0)),
+                right.to_decimal().unwrap_or(decimal::Decimal::from_int(0)),
+            );
+            if is_division && b.is_zero() {
+                return CellValue::Error(String::from("Error: Division by zero"));
+            }
+            let result = match op {
+                BinaryOp::Add => a.checked_add(&b),
+                BinaryOp::Subtract => a.checked_sub(&b),
+                BinaryOp::Multiply => a.checked_mul(&b),
+                BinaryOp::Divide => a.checked_div(&b),
+                BinaryOp::Remainder => a.checked_rem(&b),
+                _ => return unsupported(op, left, right),
+            };
+            result.map(Decimal).unwrap_or_else(overflow)
+        }
+        (op, Int(_) | Float(_) | Decimal(_), Int(_) | Float(_) | Decimal(_)) => {
+            let (a, b) = (
+                left.to_float().unwrap_or(0.0),
+                right.to_float().unwrap_or(0.0),
+            );
+            if is_division && b == 0.0 {
+                return CellValue::Error(String::from("Error: Division by zero"));
+            }
+            match op {
+                BinaryOp::Add => Float(a + b),
+                BinaryOp::Subtract => Float(a - b),
+                BinaryOp::Multiply => Float(a * b),
+                BinaryOp::Divide => Float(a / b),
+                BinaryOp::Remainder => Float(a % b),
+                _ => unsupported(op, left, right),
+            }
+        }
+
+        (BinaryOp::Add, Date(date), Int(days)) | (BinaryOp::Add, Int(days), Date(date)) => {
+            checked(date.0.checked_add(*days), |days| Date(time::Date(days)))
+        }
+        (BinaryOp::Subtract, Date(date), Int(days)) => {
+            checked(date.0.checked_sub(*days), |days| Date(time::Date(days)))
+        }
+        (BinaryOp::Subtract, Date(a), Date(b)) => checked(a.0.checked_sub(b.0), Int),
+
+        (BinaryOp::Add, Date(date), Duration(duration))
+        | (BinaryOp::Add, Duration(duration), Date(date)) => {
+            checked(date.at_midnight().0.checked_add(duration.0), |seconds| {
+                DateTime(time::DateTime(seconds))
+            })
+        }
+        (BinaryOp::Add, DateTime(moment), Duration(duration))
+        | (BinaryOp::Add, Duration(duration), DateTime(moment)) => {
+            checked(moment.0.checked_add(duration.0), |seconds| {
+                DateTime(time::DateTime(seconds))
+            })
+        }
+        (BinaryOp::Subtract, Date(date), Duration(duration)) => {
+            checked(date.at_midnight().0.checked_sub(duration.0), |seconds| {
+                DateTime(time::DateTime(seconds))
+            })
+        }
+        (BinaryOp::Subtract, DateTime(moment), Duration(duration)) => {
+            checked(moment.0.checked_sub(duration.0), |seconds| {
+                DateTime(time::DateTime(seconds))
+            })
+        }
+        (BinaryOp::Subtract, DateTime(_) | Date(_), DateTime(_) | Date(_)) => {
+            let seconds = |value: &CellValue| match value {
+                Date(date) => date.at_midnight().0,
+                DateTime(moment) => moment.0,
+                _ => 0,
+            };
+            checked(seconds(left).checked_sub(seconds(right)), |seconds| {
+                Duration(time::Duration(seconds))
+            })
+        }
+
+        (BinaryOp::Add, Duration(a), Duration(b)) => checked(a.0.checked_add(b.0), |seconds| {
+            Duration(time::Duration(seconds))
+        }),
+        (BinaryOp::Subtract, Duration(a), Duration(b)) => {
+            checked(a.0.checked_sub(b.0), |seconds| {
+                Duration(time::Duration(seconds))
+            })
+        }
+        (BinaryOp::Divide, Duration(a), Duration(b)) => Float(a.0 as f64 / b.0 as f64),
+        (BinaryOp::Multiply, Duration(duration), factor)
+        | (BinaryOp::Multiply, factor, Duration(duration))
+            if factor.is_number() =>
+        {
+            let seconds = duration.0 as f64 * factor.to_float().unwrap_or(0.0);
+            Duration(time::Duration(seconds.round() as i64))
+        }
+        (BinaryOp::Divide, Duration(duration), divisor) if divisor.is_number() => {
+            let divisor = divisor.to_float().unwrap_or(0.0);
+            if divisor == 0.0 {
+                return CellValue::Error(String::from("Error: Division by zero"));
+            }
+            Duration(time::Duration((duration.0 as f64 / divisor).round() as i64))
+        }
+
+        _ => unsupported(op, left, right),
+    }
+}
+
+fn unsupported(op: BinaryOp, left: &CellValue, right: &CellValue) -> CellValue {
+    CellValue::Error(format!(
+        "Error: Cannot apply {} to {} and {}",
+        op, left, right
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(text: &str) -> CellValue {
+        CellValue::Date(time::Date::parse(text).unwrap())
+    }
+
+    fn date_time(text: &str) -> CellValue {
+        CellValue::DateTime(time::DateTime::parse(text).unwrap())
+    }
+
+    fn duration(text: &str) -> CellValue {
+        CellValue::Duration(time::Duration::parse(text).unwrap())
+    }
+
+    #[test]
+    fn dates_and_days() {
+        let add = |left, right| arithmetic(BinaryOp::Add, &left, &right);
+        assert_eq!(
+            add(date("2024-02-28"), CellValue::Int(1)),
+            date("2024-02-29")
+        );
+        assert_eq!(
+            add(CellValue::Int(2), date("2023-12-31")),
+            date("2024-01-02")
+        );
+        assert_eq!(
+            arithmetic(BinaryOp::Subtract, &date("2024-03-01"), &CellValue::Int(1)),
+            date("2024-02-29")
+        );
+        assert_eq!(
+            arithmetic(BinaryOp::Subtract, &date("2024-03-01"), &date("2023-03-01")),
+            CellValue::Int(366)
+        );
+    }
+
+    #[test]
+    fn date_times_and_durations() {
+        assert_eq!(
+            arithmetic(BinaryOp::Add, &date("2024-03-01"), &duration("1h30m")),
+            date_time("2024-03-01T01:30")
+        );
+        assert_eq!(
+            arithmetic(
+                BinaryOp::Subtract,
+                &date_time("2024-03-01T01:00"),
+                &duration("2h")
+            ),
+            date_time("2024-02-29T23:00")
+        );
+        assert_eq!(
+            arithmetic(
+                BinaryOp::Subtract,
+                &date_time("2024-03-01T12:00"),
+                &date("2024-03-01")
+            ),
+            duration("12h")
+        );
+        assert_eq!(
+            arithmetic(BinaryOp::Multiply, &duration("1h"), &CellValue::Float(1.5)),
+            duration("90m")
+        );
+        assert_eq!(
+            arithmetic(BinaryOp::Divide, &duration("1h"), &duration("30m")),
+            CellValue::Float(2.0)
+        );
+    }
+
+    #[test]
+    fn unsupported_date_arithmetic() {
+        assert!(matches!(
+            arithmetic(BinaryOp::Add, &date("2024-03-01"), &date("2024-03-01")),
+            CellValue::Error(_)
+        ));
+        assert!(matches!(
+            arithmetic(BinaryOp::Divide, &duration("1h"), &CellValue::Int(0)),
+            CellValue::Error(_)
+        ));
+        assert!(matches!(
+            arithmetic(
+                BinaryOp::Add,
+                &date("2024-03-01"),
+                &CellValue::Int(i64::MAX)
+            ),
+            CellValue::Error(_)
+        ));
+    }
+}
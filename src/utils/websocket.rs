@@ -0,0 +1,439 @@
+use crate::utils::auth::{Access, Auth};
+use crate::utils::base64;
+use crate::utils::database::{pos_to_cell_id, reference_region, resolve_name};
+use crate::utils::engine::{subscribe_changes, Changes};
+use crate::utils::http_server::{read_head, ConnectionSlot, READ_TIMEOUT};
+use crate::utils::json_protocol::typed_value;
+use crate::utils::metrics::open_connection;
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Appended to the client's key to prove the server speaks WebSocket (RFC 6455)
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Largest frame accepted from a client, enough for any subscription message
+const MAX_FRAME_LENGTH: u64 = 64 * 1024;
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+// WebSocket front-end streaming live cell updates
+// Clients send text frames holding `{"subscribe": "A1_C5"}` or `{"unsubscribe": "A1_C5"}`,
+// ranges can also be cells, names or table columns
+// After every request that recomputes cells in a subscribed range the client receives
+//   {"range": "A1_C5", "changes": [{"cell": "B2", "value": {"type": "int", "value": 7}}]}
+// With authentication, the upgrade request carries an `Authorization` header as for HTTP,
+// and only cells the user may read are streamed
+pub fn serve_websocket(listener: TcpListener, auth: Option<Arc<Auth>>) {
+    let open = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming().flatten() {
+        let slot = match ConnectionSlot::take(&open) {
+            Some(slot) => slot,
+            None => {
+                ConnectionSlot::refuse(stream);
+                continue;
+            }
+        };
+        let auth = auth.clone();
+        thread::spawn(move || {
+            let _slot = slot;
+            let _ = handle_connection(stream, auth.as_deref());
+        });
+    }
+}
+
+fn handle_connection(stream: TcpStream, auth: Option<&Auth>) -> io::Result<()> {
+    let _open = open_connection();
+    // Only the handshake is timed, a subscribed client may stay silent for as long as it likes
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let writer = Arc::new(Mutex::new(stream));
+    let access = match accept_handshake(&mut reader, &mut writer.lock().unwrap(), auth)? {
+        Some(access) => Arc::new(access),
+        None => return Ok(()),
+    };
+    writer.lock().unwrap().set_read_timeout(None)?;
+
+    // Subscribe before reading any message so that no change is missed
+    let subscriptions: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+    let changes = subscribe_changes();
+    {
+        let writer = Arc::clone(&writer);
+        let subscriptions = Arc::clone(&subscriptions);
+        let access = Arc::clone(&access);
+        thread::spawn(move || {
+            for changes in changes {
+                let ranges = subscriptions.lock().unwrap().clone();
+                for message in change_messages(&ranges, &changes, &access) {
+                    let mut writer = writer.lock().unwrap();
+                    if write_frame(&mut *writer, OPCODE_TEXT, message.as_bytes()).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    let result = read_messages(&mut reader, &writer, &subscriptions, &access);
+    // Closing the socket also stops the thread sending changes
+    let _ = writer.lock().unwrap().shutdown(Shutdown::Both);
+    result
+}
+
+// Answer the frames of a client until it closes the connection
+fn read_messages(
+    reader: &mut impl Read,
+    writer: &Mutex<TcpStream>,
+    subscriptions: &Mutex<Vec<String>>,
+    access: &Access,
+) -> io::Result<()> {
+    loop {
+        let (opcode, payload) = read_frame(reader)?;
+        let mut writer = writer.lock().unwrap();
+        match opcode {
+            OPCODE_TEXT => {
+                let reply = handle_message(&payload, subscriptions, access);
+                write_frame(&mut *writer, OPCODE_TEXT, reply.to_string().as_bytes())?;
+            }
+            OPCODE_PING => write_frame(&mut *writer, OPCODE_PONG, &payload)?,
+            OPCODE_CLOSE => return write_frame(&mut *writer, OPCODE_CLOSE, &[]),
+            _ => {}
+        }
+    }
+}
+
+// Read the HTTP upgrade request and answer it with the access of the client,
+// `None` if it was not a WebSocket upgrade or lacked valid credentials
+fn accept_handshake(
+    reader: &mut impl BufRead,
+    writer: &mut TcpStream,
+    auth: Option<&Auth>,
+) -> io::Result<Option<Access>> {
+    let mut key = None;
+    let mut authorization = None;
+    for line in read_head(reader)? {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(value.trim().to_string());
+            } else if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    let access = match auth {
+        Some(auth) => authorization
+            .and_then(|header| auth.authorize(&header))
+            .map(|(_, access)| access),
+        None => Some(Access::full()),
+    };
+    match (key, access) {
+        (Some(_), None) => {
+            write!(
+                writer,
+                "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Basic realm=\"rsheet\"\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            )?;
+            Ok(None)
+        }
+        (Some(key), access) => {
+            let accept = base64::encode(&sha1(format!("{}{}", key, HANDSHAKE_GUID).as_bytes()));
+            write!(
+                writer,
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                accept
+            )?;
+            Ok(access)
+        }
+        (None, _) => {
+            let body = "Expected a WebSocket upgrade";
+            write!(
+                writer,
+                "HTTP/1.1 400 Bad Request\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )?;
+            Ok(None)
+        }
+    }
+}
+
+// Apply a subscription message and give the reply
+fn handle_message(payload: &[u8], subscriptions: &Mutex<Vec<String>>, access: &Access) -> Value {
+    let message: Value = match serde_json::from_slice(payload) {
+        Ok(message) => message,
+        Err(e) => return json!({"error": e.to_string()}),
+    };
+    let mut subscriptions = subscriptions.lock().unwrap();
+    if let Some(range) = message.get("subscribe").and_then(Value::as_str) {
+        match reference_region(&resolve_name(range)) {
+            None => return json!({"error": format!("Invalid range: {}", range)}),
+            Some(region) if !access.can_read_region(&region) => {
+                return json!({"error": format!("Permission denied: {}", range)})
+            }
+            Some(_) => {}
+        }
+        if !subscriptions.iter().any(|subscribed| subscribed == range) {
+            subscriptions.push(range.to_string());
+        }
+        json!({"subscribed": range})
+    } else if let Some(range) = message.get("unsubscribe").and_then(Value::as_str) {
+        subscriptions.retain(|subscribed| subscribed != range);
+        json!({"unsubscribed": range})
+    } else {
+        json!({"error": "Expected `subscribe` or `unsubscribe`"})
+    }
+}
+
+// One message for every subscribed range with recomputed cells in it
+// Ranges are resolved again for every batch so that names and shifted tables are followed
+fn change_messages(ranges: &[String], changes: &Changes, access: &Access) -> Vec<String> {
+    ranges
+        .iter()
+        .filter_map(|range| {
+            let region = reference_region(&resolve_name(range))?;
+            let changed: Vec<_> = changes
+                .iter()
+                .filter(|(position, _)| region.contains(position) && access.can_read(&[*position]))
+                .map(|(position, value)| {
+                    json!({"cell": pos_to_cell_id(position), "value": typed_value(value)})
+                })
+                .collect();
+            (!changed.is_empty()).then(|| json!({"range": range, "changes": changed}).to_string())
+        })
+        .collect()
+}
+
+// Read a whole frame, unmasking the payload
+// Clients must mask every frame, RFC 6455, section 5.1, so an unmasked one closes the connection
+// Fragmented messages are not used by subscription messages and are not reassembled
+fn read_frame(reader: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0; 2];
+    reader.read_exact(&mut header)?;
+    let opcode = header[0] & 0x0F;
+    if header[1] & 0x80 == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Unmasked frame"));
+    }
+    let length = match header[1] & 0x7F {
+        126 => {
+            let mut length = [0; 2];
+            reader.read_exact(&mut length)?;
+            u16::from_be_bytes(length) as u64
+        }
+        127 => {
+            let mut length = [0; 8];
+            reader.read_exact(&mut length)?;
+            u64::from_be_bytes(length)
+        }
+        length => length as u64,
+    };
+    if length > MAX_FRAME_LENGTH {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Frame too long"));
+    }
+
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask)?;
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload)?;
+    for (index, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[index % 4];
+    }
+    Ok((opcode, payload))
+}
+
+// Write an unmasked, unfragmented frame, as a server does
+fn write_frame(writer: &mut impl Write, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length if length < 126 => frame.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend((length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend((length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+// SHA-1 digest, only used for the handshake
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend((data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for (index, word) in block.chunks(4).enumerate() {
+            words[index] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for index in 16..80 {
+            words[index] =
+                (words[index - 3] ^ words[index - 8] ^ words[index - 14] ^ words[index - 16])
+                    .rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (index, word) in words.iter().enumerate() {
+            let (f, k) = match index {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (value, added) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(added);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (chunk, value) in digest.chunks_mut(4).zip(state) {
+        chunk.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::value::CellValue;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn sha1_digests() {
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(
+            hex(&sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(
+            hex(&sha1(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+        assert_eq!(
+            hex(&sha1(&[b'a'; 1000])),
+            "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+        );
+    }
+
+    #[test]
+    fn handshake_accept_key() {
+        // The example of RFC 6455, section 1.3
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        let accept = base64::encode(&sha1(format!("{}{}", key, HANDSHAKE_GUID).as_bytes()));
+        assert_eq!(accept, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn read_masked_frame() {
+        // A masked "Hello" from a client, RFC 6455, section 5.7
+        let frame = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let (opcode, payload) = read_frame(&mut &frame[..]).unwrap();
+        assert_eq!(opcode, OPCODE_TEXT);
+        assert_eq!(payload, b"Hello");
+    }
+
+    #[test]
+    fn unmasked_frames_are_refused() {
+        // The same "Hello" unmasked, as only a server may send it
+        let frame = [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        let e = read_frame(&mut &frame[..]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn write_frame_lengths() {
+        let mut frame = Vec::new();
+        write_frame(&mut frame, OPCODE_TEXT, b"Hello").unwrap();
+        assert_eq!(frame, [0x81, 0x05, b'H', b'e', b'l', b'l', b'o']);
+
+        for length in [125, 126, 300, u16::MAX as usize, u16::MAX as usize + 1] {
+            let payload = vec![b'x'; length];
+            let mut frame = Vec::new();
+            write_frame(&mut frame, OPCODE_PONG, &payload).unwrap();
+            let header = match length {
+                0..=125 => 2,
+                126..=0xFFFF => 4,
+                _ => 10,
+            };
+            assert_eq!(frame.len(), header + length);
+            // Read back as a client would send it, masked with zeros
+            frame[1] |= 0x80;
+            frame.splice(header..header, [0; 4]);
+            if length as u64 <= MAX_FRAME_LENGTH {
+                assert_eq!(read_frame(&mut &frame[..]).unwrap(), (OPCODE_PONG, payload));
+            } else {
+                assert!(read_frame(&mut &frame[..]).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn changes_in_subscribed_ranges() {
+        let changes = vec![
+            ((1, 2), CellValue::Int(7)),
+            ((5, 9), CellValue::Int(1)),
+            ((1, 1_000_000), CellValue::Int(2)),
+        ];
+        let ranges = [
+            String::from("A1_C5"),
+            String::from("B_B"),
+            String::from("D1_D3"),
+        ];
+        let messages = change_messages(&ranges, &changes, &Access::full());
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            serde_json::from_str::<Value>(&messages[0]).unwrap(),
+            json!({
+                "range": "A1_C5",
+                "changes": [{"cell": "B2", "value": {"type": "int", "value": 7}}]
+            })
+        );
+        let column: Value = serde_json::from_str(&messages[1]).unwrap();
+        assert_eq!(column["range"], "B_B");
+        assert_eq!(column["changes"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn subscribe_whole_column() {
+        let subscriptions = Mutex::new(Vec::new());
+        let reply = handle_message(br#"{"subscribe": "B_B"}"#, &subscriptions, &Access::full());
+        assert_eq!(reply, json!({"subscribed": "B_B"}));
+        let reply = handle_message(br#"{"subscribe": "1A"}"#, &subscriptions, &Access::full());
+        assert_eq!(reply, json!({"error": "Invalid range: 1A"}));
+        assert_eq!(*subscriptions.lock().unwrap(), vec![String::from("B_B")]);
+    }
+}